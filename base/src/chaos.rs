@@ -0,0 +1,67 @@
+//! A stream for tests that does everything a real socket is allowed to: reads and writes that only get part
+//! of the way, calls interrupted by a signal, calls that take a while, and, for nonblocking streams, calls that
+//! would block.
+//!
+//! It goes through a fixed cycle rather than rolling dice, so a test that fails keeps failing the same way.
+
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+/// How far every call gets, in turn.
+const PIECES: [usize; 5] = [1, 7, 3, 64, 2];
+
+pub struct Chaos<S> {
+    inner: S,
+    calls: usize,
+    would_block: bool,
+}
+
+impl<S> Chaos<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, calls: 0, would_block: false }
+    }
+
+    /// Also fails every few calls with [`WouldBlock`](io::ErrorKind::WouldBlock), like a nonblocking stream
+    /// whose buffer is full or empty.
+    pub fn nonblocking(inner: S) -> Self {
+        Self { inner, calls: 0, would_block: true }
+    }
+
+    /// How much of a call for `len` bytes to go through with, or the error to fail it with instead.
+    fn next(&mut self, len: usize) -> io::Result<usize> {
+        self.calls += 1;
+
+        if self.calls % 3 == 0 {
+            return Err(io::ErrorKind::Interrupted.into())
+        }
+
+        if self.would_block && self.calls % 5 == 0 {
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        if self.calls % 7 == 0 {
+            thread::sleep(Duration::from_micros(100));
+        }
+
+        Ok(len.min(PIECES[self.calls % PIECES.len()]))
+    }
+}
+
+impl<S: Read> Read for Chaos<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.next(buf.len())?;
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl<S: Write> Write for Chaos<S> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = self.next(data.len())?;
+        self.inner.write(&data[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,308 @@
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::{ArgMatches, Args, Parser, Subcommand};
+use clap::parser::ValueSource;
+use hv_sock::SocketAddr;
+use crate::{Compression, FrameHeader, framing};
+use crate::payload::Payload;
+use crate::report::Format;
+use crate::throughput::{MAX_MESSAGE_SIZE, Request};
+use crate::transport::{self, TransportAddr};
+
+const ENVIRONMENT: &str = "\
+Options marked with [env: ...] can also be set through that environment variable. Whatever is given on the \
+command line wins over the environment, which wins over the default.
+
+WAYDOWS_LOG sets which log messages are shown, in the same syntax as RUST_LOG, which is used if it is unset.";
+
+#[derive(Parser)]
+#[command(
+    name = "waydows",
+    version,
+    about = "Tooling for running Wayland applications on the Windows desktop",
+    after_help = ENVIRONMENT,
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Measure how fast frames make it across the transport.
+    #[command(subcommand)]
+    Bench(BenchCommand),
+}
+
+#[derive(Subcommand)]
+pub enum BenchCommand {
+    /// Generate frames and stream them to every client that connects.
+    Server(ServerArgs),
+
+    /// Connect to a benchmark server and report how fast frames arrive.
+    Client(ClientArgs),
+
+    /// Saturate the link with fixed-size messages in both directions and report the sustained throughput.
+    #[command(subcommand)]
+    Throughput(ThroughputCommand),
+
+    /// Run the throughput measurement over hv-sock, TCP loopback and a Unix socket and compare them.
+    Compare(CompareArgs),
+}
+
+#[derive(Subcommand)]
+pub enum ThroughputCommand {
+    /// Take part in a throughput run for every connection, running them side by side.
+    Server(ThroughputServerArgs),
+
+    /// Run a throughput measurement against a throughput server.
+    Client(ThroughputClientArgs),
+}
+
+#[derive(Args)]
+pub struct ServerArgs {
+    #[command(flatten)]
+    pub addr: AddrArgs,
+
+    #[command(flatten)]
+    pub screen: ScreenArgs,
+
+    /// How many frames to send to each client per second.
+    #[arg(long, env = "WAYDOWS_FPS", default_value_t = 60.0, value_parser = parse_rate)]
+    pub fps: f64,
+
+    /// How to compress frames before they go over the wire. The client picks this up during the handshake.
+    #[arg(long, env = "WAYDOWS_COMPRESSION", value_enum, default_value_t)]
+    pub compression: Compression,
+
+    /// What to fill frames with. Every frame carries a checksum the client verifies regardless.
+    #[arg(long, value_enum, default_value_t)]
+    pub payload: Payload,
+
+    /// Generate random payloads from this seed, so runs are reproducible and clients can verify frames.
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Lower the frame rate of clients whose link can't keep up with it, instead of blocking on them, and raise
+    /// it back towards --fps once it can.
+    #[arg(long)]
+    pub adaptive_fps: bool,
+
+    /// Turn away clients connecting while this many are already being served.
+    #[arg(long)]
+    pub max_clients: Option<NonZeroUsize>,
+
+    /// Cap how fast frames are sent to each client, in megabits per second after compression.
+    #[arg(long, value_parser = parse_rate)]
+    pub bandwidth_limit: Option<f64>,
+
+    #[command(flatten)]
+    pub workers: WorkerArgs,
+}
+
+#[derive(Args)]
+pub struct WorkerArgs {
+    /// How many threads to generate frames on, one per core by default.
+    #[arg(long)]
+    pub workers: Option<NonZeroUsize>,
+
+    /// Pin every frame generating thread to a core, going round the cores if there are more threads than them.
+    #[arg(long)]
+    pub pin_workers: bool,
+}
+
+#[derive(Args)]
+pub struct ClientArgs {
+    #[command(flatten)]
+    pub addr: AddrArgs,
+
+    /// How many seconds after connecting to leave out of the steady state statistics.
+    #[arg(long, default_value_t = 0)]
+    pub warmup: u64,
+
+    /// Check every frame byte for byte against what the server's seed generates. Needs a server run with --seed.
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Stop after this many seconds instead of running until interrupted.
+    #[arg(long)]
+    pub duration: Option<NonZeroU64>,
+
+    /// Stop after receiving this many frames instead of running until interrupted.
+    #[arg(long)]
+    pub frames: Option<NonZeroU64>,
+
+    /// Also write per-second and summary statistics to a file in this format.
+    #[arg(long, value_enum)]
+    pub output: Option<Format>,
+
+    /// Where to write the statistics to, `waydows-bench.<format>` by default.
+    #[arg(long, requires = "output")]
+    pub output_file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct ThroughputServerArgs {
+    #[command(flatten)]
+    pub addr: AddrArgs,
+}
+
+#[derive(Args)]
+pub struct ThroughputClientArgs {
+    #[command(flatten)]
+    pub addr: AddrArgs,
+
+    #[command(flatten)]
+    pub run: ThroughputArgs,
+
+    /// How many connections to run in parallel. Each one reports on its own, followed by what they got through
+    /// together.
+    #[arg(long, default_value = "1")]
+    pub connections: NonZeroUsize,
+}
+
+#[derive(Args)]
+pub struct CompareArgs {
+    /// The hv-sock address to loop back through. Both the listening and the connecting end run in this process.
+    #[command(flatten)]
+    pub addr: AddrArgs,
+
+    #[command(flatten)]
+    pub run: ThroughputArgs,
+}
+
+#[derive(Args)]
+pub struct ThroughputArgs {
+    /// Size of every message in bytes.
+    #[arg(long, default_value_t = 64 * 1024, value_parser = clap::value_parser!(u32).range(1..=MAX_MESSAGE_SIZE as i64))]
+    pub message_size: u32,
+
+    /// How long to send for in each direction, in seconds.
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub duration: u64,
+
+    /// Batch messages into fewer writes, holding each one back for at most this many microseconds.
+    #[arg(long)]
+    pub coalesce: Option<u64>,
+}
+
+impl ThroughputArgs {
+    pub fn request(&self) -> Request {
+        Request {
+            message_size: self.message_size,
+            duration: Duration::from_secs(self.duration),
+            coalesce: self.coalesce.map(Duration::from_micros),
+        }
+    }
+}
+
+/// The size of the frames the server sends.
+#[derive(Args)]
+pub struct ScreenArgs {
+    /// Width of a frame in pixels.
+    #[arg(long)]
+    pub width: NonZeroUsize,
+
+    /// Height of a frame in pixels.
+    #[arg(long)]
+    pub height: NonZeroUsize,
+}
+
+impl ScreenArgs {
+    /// How many bytes a frame takes, as long as it still fits in a message along with its header.
+    pub fn frame_len(&self) -> Result<usize, String> {
+        let (width, height) = (self.width.get(), self.height.get());
+        let max = framing::DEFAULT_MAX_LEN as usize - FrameHeader::LEN;
+
+        width.checked_mul(height)
+            .filter(|&len| len <= max)
+            .ok_or_else(|| format!("a {width}x{height} frame is bigger than the {max} bytes a message has room for"))
+    }
+}
+
+/// The slowest rate [`parse_rate`] accepts. Anything slower isn't any use, and the intervals worked out from
+/// rates close enough to zero don't fit in a [`Duration`].
+pub const MIN_RATE: f64 = 0.001;
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if (MIN_RATE..=f64::MAX).contains(&value) => Ok(value),
+        Ok(_) => Err(format!("must be a number no smaller than {MIN_RATE}")),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Where the server listens, or where the client connects to.
+#[cfg(target_os = "linux")]
+#[derive(Args)]
+pub struct AddrArgs {
+    /// The vsock port.
+    #[arg(env = "WAYDOWS_PORT", required_unless_present = "transport")]
+    port: Option<String>,
+
+    /// Go over this address instead: tcp://HOST:PORT, unix:///PATH or hvsock://PORT.
+    #[arg(long, env = "WAYDOWS_TRANSPORT")]
+    transport: Option<String>,
+}
+
+#[cfg(target_os = "linux")]
+const HV_SOCK_ARGS: &[&str] = &["port"];
+
+#[cfg(target_os = "linux")]
+impl AddrArgs {
+    pub fn socket_addr(&self) -> Result<SocketAddr, String> {
+        transport::hv_sock_addr(self.port.as_deref().ok_or("no port given")?)
+    }
+}
+
+/// Where the server listens, or where the client connects to.
+#[cfg(windows)]
+#[derive(Args)]
+pub struct AddrArgs {
+    /// The id of the VM on the other end.
+    #[arg(env = "WAYDOWS_VM_ID", required_unless_present = "transport")]
+    vm_id: Option<String>,
+
+    /// The id of the service registered under GuestCommunicationServices.
+    #[arg(env = "WAYDOWS_SERVICE_ID", required_unless_present = "transport")]
+    service_id: Option<String>,
+
+    /// Go over this address instead: tcp://HOST:PORT, unix:///PATH or hvsock://VM_ID/SERVICE_ID.
+    #[arg(long, env = "WAYDOWS_TRANSPORT")]
+    transport: Option<String>,
+}
+
+#[cfg(windows)]
+const HV_SOCK_ARGS: &[&str] = &["vm_id", "service_id"];
+
+#[cfg(windows)]
+impl AddrArgs {
+    pub fn socket_addr(&self) -> Result<SocketAddr, String> {
+        let vm_id = self.vm_id.as_deref().ok_or("no vm id given")?;
+        let service_id = self.service_id.as_deref().ok_or("no service id given")?;
+        transport::hv_sock_addr(vm_id, service_id)
+    }
+}
+
+impl AddrArgs {
+    /// The address to use, out of whatever `matches` say was given where. `--transport` wins over the hv-sock
+    /// address when both come from the same place, the command line wins over the environment otherwise.
+    pub fn addr(&self, matches: &ArgMatches) -> Result<TransportAddr, String> {
+        let hv_sock_given = HV_SOCK_ARGS.iter().any(|id| on_command_line(matches, id));
+
+        match &self.transport {
+            Some(addr) if Self::transport_given(matches) || !hv_sock_given => addr.parse(),
+            _ => self.socket_addr().map(TransportAddr::HvSock),
+        }
+    }
+
+    /// Whether `--transport` was given on the command line, rather than only through the environment.
+    pub fn transport_given(matches: &ArgMatches) -> bool {
+        on_command_line(matches, "transport")
+    }
+}
+
+fn on_command_line(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
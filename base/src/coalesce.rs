@@ -0,0 +1,168 @@
+//! Batches small writes into fewer, larger ones. Every write to an hv-sock stream is a syscall and a trip
+//! through the VM bus, which dominates the cost of messages the size of an input event.
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// How much gets batched before it's written regardless of how recent it is.
+pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+/// A buffered writer that also bounds how long data may sit in its buffer.
+///
+/// The bound is checked on every write, so it holds as long as writes keep coming. Whoever stops writing for a
+/// while has to [`flush`](Write::flush), which always writes everything out.
+pub struct CoalescingWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    max_delay: Duration,
+
+    /// When the oldest byte in `buf` was written.
+    oldest: Option<Instant>,
+}
+
+impl<W: Write> CoalescingWriter<W> {
+    pub fn new(inner: W, max_delay: Duration) -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY, inner, max_delay)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: W, max_delay: Duration) -> Self {
+        Self { inner, buf: Vec::with_capacity(capacity), max_delay, oldest: None }
+    }
+
+    /// Writes out the buffer, keeping whatever didn't make it if that fails, so a nonblocking stream that
+    /// would block doesn't lose anything.
+    fn write_buf(&mut self) -> io::Result<()> {
+        let mut written = 0;
+
+        let result = loop {
+            if written == self.buf.len() {
+                break Ok(())
+            }
+
+            match self.inner.write(&self.buf[written..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => written += len,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {},
+                Err(error) => break Err(error),
+            }
+        };
+
+        self.buf.drain(..written);
+
+        if self.buf.is_empty() {
+            self.oldest = None;
+        }
+
+        result
+    }
+}
+
+impl<W: Write> Write for CoalescingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.buf.capacity() {
+            self.write_buf()?;
+        }
+
+        // nothing to gain from copying something that fills the buffer on its own
+        if data.len() >= self.buf.capacity() {
+            return self.inner.write(data)
+        }
+
+        self.buf.extend_from_slice(data);
+
+        if self.oldest.get_or_insert_with(Instant::now).elapsed() >= self.max_delay {
+            match self.write_buf() {
+                // `data` is in the buffer, failing now would have the caller write it again
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {},
+                result => result?,
+            }
+        }
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buf()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for CoalescingWriter<W> {
+    fn drop(&mut self) {
+        // like BufWriter, errors can't be reported from here, flush explicitly to see them
+        let _ = self.write_buf();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chaos::Chaos;
+
+    /// Records the size of every write that reaches it.
+    #[derive(Default)]
+    struct Writes(Vec<usize>);
+
+    impl Write for &mut Writes {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.push(data.len());
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn small_writes_are_batched_until_full_or_flushed() {
+        let mut writes = Writes::default();
+        let mut writer = CoalescingWriter::with_capacity(10, &mut writes, Duration::from_secs(60));
+
+        for _ in 0..7 {
+            writer.write_all(&[0; 3]).unwrap();
+        }
+
+        writer.write_all(&[0; 20]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        assert_eq!(writes.0, [9, 9, 3, 20]);
+    }
+
+    #[test]
+    fn zero_delay_writes_everything_immediately() {
+        let mut writes = Writes::default();
+        let mut writer = CoalescingWriter::with_capacity(10, &mut writes, Duration::ZERO);
+
+        writer.write_all(&[0; 3]).unwrap();
+        writer.write_all(&[0; 3]).unwrap();
+        drop(writer);
+
+        assert_eq!(writes.0, [3, 3]);
+    }
+
+    #[test]
+    fn nothing_is_lost_to_short_writes_or_would_block() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut written = Vec::new();
+        let mut writer = CoalescingWriter::with_capacity(10, Chaos::nonblocking(&mut written), Duration::ZERO);
+
+        for mut chunk in data.chunks(7) {
+            while !chunk.is_empty() {
+                match writer.write(chunk) {
+                    Ok(len) => chunk = &chunk[len..],
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => {},
+                    Err(error) => panic!("{error}"),
+                }
+            }
+        }
+
+        while let Err(error) = writer.flush() {
+            assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        }
+
+        drop(writer);
+        assert_eq!(written, data);
+    }
+}
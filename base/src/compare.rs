@@ -0,0 +1,97 @@
+//! Runs the throughput measurement over every transport in turn, with both ends in this process, and prints
+//! the results side by side.
+
+use std::{env, fs, io, process, thread};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use hv_sock::SocketAddr;
+use tracing::{info, info_span, warn};
+use waydows_unix_socket::{UnixListener, UnixStream};
+use crate::cli::CompareArgs;
+use crate::throughput::{self, Request, Throughput};
+use crate::transport::{self, TransportListener};
+
+type Measurement = Result<(Throughput, Throughput), throughput::Error>;
+type Run<'a> = &'a dyn Fn() -> Measurement;
+
+fn measure<L: TransportListener>(
+    listener: L,
+    connect: impl FnOnce() -> io::Result<L::Stream>,
+    request: &Request,
+) -> Measurement {
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept()?;
+        throughput::serve(stream)
+    });
+
+    // if connecting fails the server is left blocked in accept, there's no waking it up
+    let result = throughput::run(connect()?, request);
+    server.join().unwrap()?;
+    result
+}
+
+fn hv_sock(socket_addr: &SocketAddr, request: &Request) -> Measurement {
+    measure(transport::bind_hv_sock(socket_addr)?, || hv_sock::Stream::connect(socket_addr), request)
+}
+
+fn tcp(request: &Request) -> Measurement {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let addr = listener.local_addr()?;
+
+    measure(listener, || {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }, request)
+}
+
+fn unix(request: &Request) -> Measurement {
+    let path = env::temp_dir().join(format!("waydows-compare-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let result = UnixListener::bind(&path)
+        .map_err(throughput::Error::from)
+        .and_then(|listener| measure(listener, || UnixStream::connect(&path), request));
+    let _ = fs::remove_file(&path);
+    result
+}
+
+fn print_table(results: &[(&str, (Throughput, Throughput))]) {
+    println!(
+        "{:<10} {:>14} {:>16} {:>16} {:>18}",
+        "transport", "upload (Gbps)", "download (Gbps)", "upload (msg/s)", "download (msg/s)",
+    );
+
+    for (name, (upload, download)) in results {
+        println!(
+            "{:<10} {:>14.3} {:>16.3} {:>16.0} {:>18.0}",
+            name, upload.gbps(), download.gbps(), upload.messages_per_sec(), download.messages_per_sec(),
+        );
+    }
+}
+
+pub fn compare(socket_addr: SocketAddr, args: CompareArgs) {
+    let request = args.run.request();
+    let mut results = Vec::new();
+
+    let runs: [(&str, Run); 3] = [
+        ("hv-sock", &|| hv_sock(&socket_addr, &request)),
+        ("tcp", &|| tcp(&request)),
+        ("unix", &|| unix(&request)),
+    ];
+
+    for (name, run) in runs {
+        let _span = info_span!("transport", name).entered();
+        info!(
+            message_size = request.message_size,
+            duration = ?request.duration,
+            coalesce = ?request.coalesce,
+            "starting run"
+        );
+
+        match run() {
+            Ok(result) => results.push((name, result)),
+            Err(error) => warn!(%error, "run failed, leaving it out of the comparison"),
+        }
+    }
+
+    print_table(&results);
+}
@@ -0,0 +1,209 @@
+//! Messages of any length over any stream, so the reader never has to be told how big the next one is.
+//!
+//! Every message starts with its length as a little-endian `u32`, then the CRC32 of its contents if checksums
+//! are on, then the contents. Both ends have to agree on the [`Framing`], nothing on the wire says which one
+//! is in use.
+
+use std::io::{self, BufRead, BufReader, IoSlice, Read, Write};
+use std::iter;
+use thiserror::Error;
+
+/// Keeps a bogus length from making the reader allocate whatever it says.
+pub const DEFAULT_MAX_LEN: u32 = 256 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("message of {len} bytes is over the limit of {max}")]
+    TooLong { len: usize, max: u32 },
+
+    #[error("message doesn't match its checksum")]
+    Checksum,
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Framing {
+    /// Whether every message carries a checksum of its contents.
+    pub checksums: bool,
+
+    /// The longest message either end accepts.
+    pub max_len: u32,
+}
+
+impl Framing {
+    fn prefix_len(self) -> usize {
+        if self.checksums { 8 } else { 4 }
+    }
+
+    fn check_len(self, len: usize) -> Result<(), Error> {
+        match u32::try_from(len) {
+            Ok(len) if len <= self.max_len => Ok(()),
+            _ => Err(Error::TooLong { len, max: self.max_len }),
+        }
+    }
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Self { checksums: true, max_len: DEFAULT_MAX_LEN }
+    }
+}
+
+pub struct MessageWriter<W> {
+    inner: W,
+    framing: Framing,
+}
+
+impl<W: Write> MessageWriter<W> {
+    pub fn new(inner: W, framing: Framing) -> Self {
+        Self { inner, framing }
+    }
+
+    /// Writes one message made of `parts` back to back, handing them to the stream as they are rather than
+    /// copying them into one buffer first.
+    pub fn write_message(&mut self, parts: &[&[u8]]) -> Result<(), Error> {
+        let len = parts.iter().map(|part| part.len()).sum();
+        self.framing.check_len(len)?;
+
+        let mut prefix = [0; 8];
+        prefix[..4].copy_from_slice(&(len as u32).to_le_bytes());
+
+        if self.framing.checksums {
+            let mut hasher = crc32fast::Hasher::new();
+            parts.iter().for_each(|part| hasher.update(part));
+            prefix[4..].copy_from_slice(&hasher.finalize().to_le_bytes());
+        }
+
+        let mut remaining: Vec<&[u8]> = iter::once(&prefix[..self.framing.prefix_len()])
+            .chain(parts.iter().copied())
+            .filter(|part| !part.is_empty())
+            .collect();
+        let mut first = 0;
+
+        while first < remaining.len() {
+            let slices: Vec<_> = remaining[first..].iter().map(|part| IoSlice::new(part)).collect();
+
+            match self.inner.write_vectored(&slices) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(mut written) => {
+                    for part in &mut remaining[first..] {
+                        let whole = *part;
+                        let advance = written.min(whole.len());
+                        *part = &whole[advance..];
+                        written -= advance;
+                    }
+
+                    first += remaining[first..].iter().take_while(|part| part.is_empty()).count();
+                },
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {},
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+pub struct MessageReader<R> {
+    inner: BufReader<R>,
+    framing: Framing,
+}
+
+impl<R: Read> MessageReader<R> {
+    pub fn new(inner: R, framing: Framing) -> Self {
+        Self { inner: BufReader::new(inner), framing }
+    }
+
+    /// Reads the next message into `buf`, resizing it to fit. Returns `false` if the stream ended cleanly
+    /// between two messages.
+    ///
+    /// A message that doesn't match its checksum still ends up in `buf`, and the stream is left at the start of
+    /// the next one, so [`Error::Checksum`] needn't be the end of it. After any other error the stream is
+    /// somewhere in the middle of a message and can't be read any further.
+    pub fn read_message(&mut self, buf: &mut Vec<u8>) -> Result<bool, Error> {
+        // the only place the stream may end is before the first byte of a message, anywhere else cuts one short
+        let ended = loop {
+            match self.inner.fill_buf() {
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {},
+                result => break result?.is_empty(),
+            }
+        };
+
+        if ended {
+            return Ok(false)
+        }
+
+        let mut prefix = [0; 8];
+        self.inner.read_exact(&mut prefix[..self.framing.prefix_len()])?;
+
+        let len = u32::from_le_bytes(prefix[..4].try_into().unwrap()) as usize;
+        self.framing.check_len(len)?;
+        buf.resize(len, 0);
+        self.inner.read_exact(buf)?;
+
+        if self.framing.checksums && crc32fast::hash(buf).to_le_bytes() != prefix[4..] {
+            return Err(Error::Checksum)
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chaos::Chaos;
+
+    #[test]
+    fn messages_survive_fragmented_streams() {
+        let mut wire = Vec::new();
+        let mut writer = MessageWriter::new(Chaos::new(&mut wire), Framing::default());
+        writer.write_message(&[b"header", &[7; 300]]).unwrap();
+        writer.write_message(&[]).unwrap();
+
+        let mut reader = MessageReader::new(Chaos::new(&wire[..]), Framing::default());
+        let mut buf = Vec::new();
+        assert!(reader.read_message(&mut buf).unwrap());
+        assert_eq!(buf[..6], *b"header");
+        assert_eq!(buf[6..], [7; 300]);
+        assert!(reader.read_message(&mut buf).unwrap());
+        assert!(buf.is_empty());
+        assert!(!reader.read_message(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn a_corrupted_message_leaves_the_stream_in_step() {
+        let mut wire = Vec::new();
+        let mut writer = MessageWriter::new(&mut wire, Framing::default());
+        writer.write_message(&[b"first"]).unwrap();
+        writer.write_message(&[b"second"]).unwrap();
+        wire[8] ^= 1;
+
+        let mut reader = MessageReader::new(&wire[..], Framing::default());
+        let mut buf = Vec::new();
+        assert!(matches!(reader.read_message(&mut buf), Err(Error::Checksum)));
+        assert!(reader.read_message(&mut buf).unwrap());
+        assert_eq!(buf, b"second");
+    }
+
+    #[test]
+    fn messages_over_the_limit_are_refused_on_both_ends() {
+        let framing = Framing { checksums: false, max_len: 4 };
+        let mut writer = MessageWriter::new(Vec::new(), framing);
+        assert!(matches!(writer.write_message(&[b"too", b"long"]), Err(Error::TooLong { len: 7, max: 4 })));
+
+        let wire = 7u32.to_le_bytes();
+        let mut reader = MessageReader::new(&wire[..], framing);
+        assert!(matches!(reader.read_message(&mut Vec::new()), Err(Error::TooLong { len: 7, max: 4 })));
+    }
+}
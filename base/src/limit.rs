@@ -0,0 +1,90 @@
+//! Caps how fast a stream may be written to, for when the link is shared with something else.
+
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a burst at full speed may last, as a fraction of a second's worth of bytes.
+const BURST: f64 = 0.1;
+
+/// Writes are split into pieces no larger than this, so one big frame doesn't go out in one burst and then
+/// leave the link idle for a long time.
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// A token bucket where every token is a byte.
+///
+/// Writes are allowed to go into debt, and pay it off by waiting, which keeps the caller from having to know how
+/// much it is going to write up front.
+#[derive(Debug)]
+pub struct TokenBucket {
+    bytes_per_sec: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: f64) -> Self {
+        let capacity = bytes_per_sec * BURST;
+        Self { bytes_per_sec, capacity, tokens: capacity, refilled: Instant::now() }
+    }
+
+    /// Takes `bytes` tokens, returning how long to wait before the bucket is out of debt again.
+    pub fn take(&mut self, bytes: usize) -> Duration {
+        self.take_at(bytes, Instant::now())
+    }
+
+    fn take_at(&mut self, bytes: usize, now: Instant) -> Duration {
+        let refill = (now - self.refilled).as_secs_f64() * self.bytes_per_sec;
+        self.tokens = (self.tokens + refill).min(self.capacity) - bytes as f64;
+        self.refilled = now;
+
+        match self.tokens {
+            0.0.. => Duration::ZERO,
+            debt => Duration::from_secs_f64(-debt / self.bytes_per_sec),
+        }
+    }
+}
+
+/// A writer that blocks for as long as it takes to stay within its [`TokenBucket`].
+pub struct RateLimitedWriter<W> {
+    inner: W,
+    bucket: TokenBucket,
+}
+
+impl<W: Write> RateLimitedWriter<W> {
+    pub fn new(inner: W, bytes_per_sec: f64) -> Self {
+        Self { inner, bucket: TokenBucket::new(bytes_per_sec) }
+    }
+}
+
+impl<W: Write> Write for RateLimitedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let chunk = data.len().min(MAX_CHUNK).min(self.bucket.capacity.max(1.0) as usize);
+        let written = self.inner.write(&data[..chunk])?;
+        thread::sleep(self.bucket.take(written));
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_are_free_and_debt_is_waited_off() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket { bytes_per_sec: 1000.0, capacity: 100.0, tokens: 100.0, refilled: start };
+
+        assert_eq!(bucket.take_at(100, start), Duration::ZERO);
+        assert_eq!(bucket.take_at(500, start), Duration::from_millis(500));
+
+        // half a second later the debt is paid off, but idling doesn't earn more than the capacity
+        assert_eq!(bucket.take_at(0, start + Duration::from_millis(500)), Duration::ZERO);
+        assert_eq!(bucket.take_at(200, start + Duration::from_secs(10)), Duration::from_millis(100));
+    }
+}
@@ -1,24 +1,55 @@
-use std::{env, thread};
+#[cfg(test)]
+mod chaos;
+mod cli;
+mod coalesce;
+mod compare;
+mod framing;
+mod limit;
+mod payload;
+mod pool;
+mod rate;
+mod report;
+mod shutdown;
+mod slot;
+mod stats;
+mod throughput;
+mod transport;
+mod workers;
+
+use std::{error, fmt, io, process, thread};
 use std::io::{Read, Write};
-use std::iter::Skip;
-use std::ops::ControlFlow;
+use std::num::NonZeroUsize;
+use std::ops::{ControlFlow, Range};
+use std::path::PathBuf;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
-use hv_sock::SocketAddr;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, ValueEnum};
+use clap::error::ErrorKind;
 use rand::{Rng, SeedableRng};
 use rand::rngs::SmallRng;
+use thiserror::Error;
+use tracing::{debug, error, info, info_span, trace_span, warn, Span};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::prelude::*;
+use crate::cli::{BenchCommand, Cli, ClientArgs, Command, ServerArgs, ThroughputCommand};
+use crate::framing::{Framing, MessageReader, MessageWriter};
+use crate::limit::RateLimitedWriter;
+use crate::payload::Payload;
+use crate::pool::BufferPool;
+use crate::rate::AdaptiveRate;
+use crate::report::{Record, Report};
+use crate::slot::Slots;
+use crate::stats::{LatencyRecorder, Pacing, PacingRecorder, StageRecorder, Stages, Summary};
+use crate::transport::{PeerAddr, Transport, TransportAddr, TransportListener};
+use crate::workers::Workers;
 
-fn run_every_second(iterations_per_second: f64, mut f: impl FnMut() -> ControlFlow<()>) {
-    let interval = Duration::from_secs_f64(1.0 / iterations_per_second);
+/// Calls `f` until it breaks, as many times a second as the last call returned.
+fn run_every_second(mut f: impl FnMut() -> ControlFlow<(), f64>) {
     let mut next_time = Instant::now();
 
-    loop {
-        match f() {
-            ControlFlow::Continue(()) => {},
-            ControlFlow::Break(()) => break,
-        }
-
-        next_time += interval;
+    while let ControlFlow::Continue(iterations_per_second) = f() {
+        next_time += Duration::from_secs_f64(1.0 / iterations_per_second);
 
         if let Some(wait_time) = next_time.checked_duration_since(Instant::now()) {
             thread::sleep(wait_time)
@@ -28,123 +59,1094 @@ fn run_every_second(iterations_per_second: f64, mut f: impl FnMut() -> ControlFl
     }
 }
 
-fn screen(width: usize, height: usize, thread_num: usize, rng: &mut impl Rng) -> Vec<u8> {
-    let now = Instant::now();
-    let mut screen = vec![0; width * height];
-    rng.fill_bytes(&mut screen);
+/// A generated screen, sent to the client as one message.
+struct Frame {
+    number: u64,
+    width: usize,
+    data: Vec<u8>,
+
+    /// How long generating it took, and when that was done.
+    generate: Duration,
+    generated: Instant,
+}
+
+/// What precedes every frame in its message. The sequence number counts the frames generated for the
+/// connection, so the client can tell when some were replaced by newer ones before they could be sent. The
+/// frame number is what the frame was generated from, which a client of a seeded server can regenerate it with,
+/// given the width.
+#[derive(Debug)]
+struct FrameHeader {
+    sequence: u64,
+    number: u64,
+    width: NonZeroUsize,
+
+    /// The time between frames the server was aiming for when it sent this one, which changes along with the
+    /// frame rate if it's adaptive.
+    interval: Duration,
+    timing: ServerTiming,
+}
+
+impl FrameHeader {
+    const LEN: usize = 36;
+}
+
+/// How long the server spent on a frame before it went out, so the client can tell which part of its latency
+/// comes from where. Durations rather than timestamps, the clocks on either end of the link can't be compared.
+#[derive(Debug, Clone, Copy)]
+struct ServerTiming {
+    generate: Duration,
 
-    // println!("thread {thread_num}: create and fill screen with random characters took {:?}", now.elapsed());
+    /// How long the frame waited in the client's slot to be sent.
+    queued: Duration,
 
-    screen
+    /// How long writing the previous frame took, which isn't known in time to go in that frame's own header.
+    previous_send: Option<Duration>,
 }
 
-#[derive(Default)]
-struct RunningAverage {
-    count: u32,
-    total: Duration,
+fn micros(duration: Duration) -> u32 {
+    u32::try_from(duration.as_micros()).unwrap_or(u32::MAX)
 }
 
-impl RunningAverage {
-    fn update(&mut self, duration: Duration) {
-        self.count += 1;
-        self.total += duration;
+impl Frame {
+    fn write_to(
+        &self,
+        writer: &mut MessageWriter<impl Write>,
+        sequence: u64,
+        interval: Duration,
+        previous_send: Option<Duration>,
+    ) -> Result<(), framing::Error> {
+        let mut header = [0; FrameHeader::LEN];
+        header[..8].copy_from_slice(&sequence.to_le_bytes());
+        header[8..16].copy_from_slice(&self.number.to_le_bytes());
+        header[16..20].copy_from_slice(&(self.width as u32).to_le_bytes());
+        header[20..24].copy_from_slice(&micros(self.generate).to_le_bytes());
+        header[24..28].copy_from_slice(&micros(self.generated.elapsed()).to_le_bytes());
+
+        // no write takes less than a microsecond, so zero is free to mean there was no previous frame
+        let previous_send = previous_send.map_or(0, |send| micros(send).max(1));
+        header[28..32].copy_from_slice(&previous_send.to_le_bytes());
+        header[32..].copy_from_slice(&micros(interval).to_le_bytes());
+        writer.write_message(&[&header, &self.data])
     }
 
-    fn get(&self) -> Option<Duration> {
-        if self.count == 0 {
-            None
-        } else {
-            Some(self.total / self.count)
+    /// Reads a frame into `buf`, header and all, or returns `None` if the stream ended cleanly between two
+    /// frames. The screen itself is everything in `buf` after the header.
+    ///
+    /// A frame that doesn't match its checksum fails with [`framing::Error::Checksum`] before anything in it is
+    /// looked at, header included, and leaves the stream at the start of the next frame.
+    fn read_from(
+        reader: &mut MessageReader<impl Read>,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<FrameHeader>, framing::Error> {
+        if !reader.read_message(buf)? {
+            return Ok(None)
+        }
+
+        let invalid = |message| framing::Error::Io(io::Error::new(io::ErrorKind::InvalidData, message));
+        let header = buf.get(..FrameHeader::LEN).ok_or_else(|| invalid("message is too short to be a frame"))?;
+        let micros = |range: Range<usize>| u32::from_le_bytes(header[range].try_into().unwrap());
+
+        Ok(Some(FrameHeader {
+            sequence: u64::from_le_bytes(header[..8].try_into().unwrap()),
+            number: u64::from_le_bytes(header[8..16].try_into().unwrap()),
+            width: NonZeroUsize::new(micros(16..20) as usize).ok_or_else(|| invalid("frame has no width"))?,
+            interval: Duration::from_micros(micros(32..36).into()),
+            timing: ServerTiming {
+                generate: Duration::from_micros(micros(20..24).into()),
+                queued: Duration::from_micros(micros(24..28).into()),
+                previous_send: match micros(28..32) {
+                    0 => None,
+                    send => Some(Duration::from_micros(send.into())),
+                },
+            },
+        }))
+    }
+}
+
+#[tracing::instrument(level = "trace", skip(data, rng))]
+fn screen(
+    mut data: Vec<u8>,
+    width: usize,
+    payload: Payload,
+    frame_number: u64,
+    thread_num: usize,
+    rng: &mut impl Rng,
+) -> Frame {
+    let started = Instant::now();
+    payload.fill(&mut data, width, frame_number, rng);
+    Frame { number: frame_number, width, data, generate: started.elapsed(), generated: Instant::now() }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum Compression {
+    None,
+    #[default]
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    const ALL: [Self; 3] = [Self::None, Self::Lz4, Self::Zstd];
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::None),
+            1 => Some(Self::Lz4),
+            2 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    fn as_byte(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+            Self::Zstd => 2,
+        }
+    }
+
+    fn flag(self) -> u8 {
+        1 << self.as_byte()
+    }
+
+    fn encoder<W: Write>(self, stream: W) -> io::Result<Encoder<W>> {
+        Ok(match self {
+            Self::None => Encoder::None(stream),
+            Self::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(stream)),
+            Self::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(stream, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+        })
+    }
+
+    fn decoder<'a>(self, stream: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Self::None => Box::new(stream),
+            Self::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(stream)),
+            Self::Zstd => Box::new(zstd::stream::read::Decoder::new(stream)?),
+        })
+    }
+}
+
+/// Compresses whatever is written to it. Flushing only gets the data so far out, the end of the stream has to be
+/// [finished](Self::finish) for the decoder on the other end to see that it ended cleanly.
+enum Encoder<W: Write> {
+    None(W),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Self::None(stream) => Ok(stream),
+            Self::Lz4(encoder) => Ok(encoder.finish()?),
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(stream) => stream.write(buf),
+            Self::Lz4(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(stream) => stream.flush(),
+            Self::Lz4(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Lz4 => "lz4",
+            Self::Zstd => "zstd",
+        })
+    }
+}
+
+const MAGIC: [u8; 4] = *b"WDWS";
+const PROTOCOL_VERSION: u16 = 8;
+
+/// What can go wrong while the two ends of the frame benchmark agree on how to talk.
+#[derive(Debug, Error)]
+enum ProtocolError {
+    #[error("{0} is not speaking the waydows benchmark protocol")]
+    NotWaydows(&'static str),
+
+    #[error("{peer} speaks protocol version {version}, but this build only speaks version {PROTOCOL_VERSION}")]
+    Version { peer: &'static str, version: u16 },
+
+    #[error("server already has as many clients as it takes")]
+    ServerFull,
+
+    #[error("server sent unknown status {0}")]
+    UnknownStatus(u8),
+
+    #[error("server picked unknown compression {0}")]
+    UnknownCompression(u8),
+
+    #[error("server fills frames with unknown payload {0}")]
+    UnknownPayload(u8),
+
+    #[error("server aims for a frame rate of {0}, which isn't one")]
+    InvalidFps(f64),
+
+    #[error("client can't decode {0} compression")]
+    UnsupportedCompression(Compression),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The preamble both ends send before any frame data: magic, protocol version and one byte
+/// of capabilities. The client sends the set of compressions it can decode as flags, the
+/// server replies with the one it is going to use.
+///
+/// Both sides write their preamble before reading the peer's, so either end can report a
+/// version mismatch instead of just seeing the connection drop. After it the server sends
+/// one byte saying whether it accepted the client and, if it did, the frame rate it starts
+/// out aiming for and what it fills frames with. Every frame header carries the interval it
+/// was aiming for by the time it sent that frame, which is what pacing is judged against.
+#[derive(Debug)]
+struct Preamble {
+    version: u16,
+    capabilities: u8,
+}
+
+impl Preamble {
+    const LEN: usize = 7;
+
+    fn new(capabilities: u8) -> Self {
+        Self { version: PROTOCOL_VERSION, capabilities }
+    }
+
+    fn write_to(&self, mut stream: impl Write) -> io::Result<()> {
+        let mut buf = [0; Self::LEN];
+        buf[..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_le_bytes());
+        buf[6] = self.capabilities;
+        stream.write_all(&buf)
+    }
+
+    fn read_from(mut stream: impl Read, peer: &'static str) -> Result<Self, ProtocolError> {
+        let mut buf = [0; Self::LEN];
+        stream.read_exact(&mut buf)?;
+
+        if buf[..4] != MAGIC {
+            return Err(ProtocolError::NotWaydows(peer));
         }
+
+        let version = u16::from_le_bytes([buf[4], buf[5]]);
+
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::Version { peer, version });
+        }
+
+        Ok(Self { version, capabilities: buf[6] })
+    }
+}
+
+const ACCEPTED: u8 = 0;
+const SERVER_FULL: u8 = 1;
+
+/// What the server tells the client about the frames it's going to send.
+#[derive(Debug, Clone, Copy)]
+struct StreamInfo {
+    compression: Compression,
+    fps: f64,
+    payload: Payload,
+
+    /// What frames are generated from, if they can be reproduced at all.
+    seed: Option<u64>,
+}
+
+fn client_handshake(mut stream: impl Read + Write) -> Result<StreamInfo, ProtocolError> {
+    let supported = Compression::ALL.iter().fold(0, |flags, compression| flags | compression.flag());
+    Preamble::new(supported).write_to(&mut stream)?;
+
+    let preamble = Preamble::read_from(&mut stream, "server")?;
+    let mut status = [0];
+    stream.read_exact(&mut status)?;
+
+    match status[0] {
+        ACCEPTED => {},
+        SERVER_FULL => return Err(ProtocolError::ServerFull),
+        status => return Err(ProtocolError::UnknownStatus(status)),
+    }
+
+    let compression = Compression::from_byte(preamble.capabilities)
+        .ok_or(ProtocolError::UnknownCompression(preamble.capabilities))?;
+
+    let mut buf = [0; 18];
+    stream.read_exact(&mut buf)?;
+    let fps = f64::from_le_bytes(buf[..8].try_into().unwrap());
+
+    if !(cli::MIN_RATE..=f64::MAX).contains(&fps) {
+        return Err(ProtocolError::InvalidFps(fps));
+    }
+
+    let payload = Payload::from_byte(buf[8]).ok_or(ProtocolError::UnknownPayload(buf[8]))?;
+    let seed = (buf[9] != 0).then(|| u64::from_le_bytes(buf[10..].try_into().unwrap()));
+
+    Ok(StreamInfo { compression, fps, payload, seed })
+}
+
+fn server_handshake(mut stream: impl Read + Write, info: &StreamInfo) -> Result<(), ProtocolError> {
+    Preamble::new(info.compression.as_byte()).write_to(&mut stream)?;
+    stream.write_all(&[ACCEPTED])?;
+
+    let mut buf = [0; 18];
+    buf[..8].copy_from_slice(&info.fps.to_le_bytes());
+    buf[8] = info.payload.as_byte();
+    buf[9] = info.seed.is_some() as u8;
+    buf[10..].copy_from_slice(&info.seed.unwrap_or(0).to_le_bytes());
+    stream.write_all(&buf)?;
+
+    let preamble = Preamble::read_from(&mut stream, "client")?;
+
+    if preamble.capabilities & info.compression.flag() == 0 {
+        return Err(ProtocolError::UnsupportedCompression(info.compression));
+    }
+
+    Ok(())
+}
+
+/// Turns a client away, so it reports why instead of just seeing the connection drop. Its preamble is read
+/// before the connection closes, because closing a TCP connection with data still unread resets it, and the
+/// client would see that instead of the reason.
+fn reject_client(mut stream: impl Read + Write) -> io::Result<()> {
+    Preamble::new(0).write_to(&mut stream)?;
+    stream.write_all(&[SERVER_FULL])?;
+    stream.flush()?;
+    stream.read_exact(&mut [0; Preamble::LEN])
+}
+
+/// Counts the bytes that actually went over the wire, before decompression.
+struct CountingReader<'a, R> {
+    inner: R,
+    count: &'a AtomicU64,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+fn compression_ratio(compressed: &AtomicU64, decompressed: &AtomicU64) -> Option<f64> {
+    match compressed.load(Ordering::Relaxed) {
+        0 => None,
+        compressed => Some(decompressed.load(Ordering::Relaxed) as f64 / compressed as f64),
+    }
+}
+
+/// Why a frame benchmark run couldn't start, or stopped early.
+#[derive(Debug, Error)]
+enum BenchError {
+    #[error(transparent)]
+    Transport(#[from] transport::Error),
+
+    #[error("handshake failed")]
+    Handshake(#[from] ProtocolError),
+
+    #[error("failed to set up {0} compression")]
+    Compression(Compression, #[source] io::Error),
+
+    #[error("failed to create {}", .path.display())]
+    Report { path: PathBuf, source: io::Error },
+
+    #[error("failed to receive frame")]
+    Receive(#[source] framing::Error),
+}
+
+/// The report printed once a client run is over, meant for reading rather than for tooling.
+fn print_summary(
+    elapsed: Duration,
+    (latency, pacing, stages): (Summary, Pacing, Stages),
+    steady: Option<(Summary, Pacing, Stages)>,
+    corrupted: u64,
+    mismatched: Option<u64>,
+) {
+    println!("{:<16} {elapsed:.1?}", "ran for");
+    println!("{:<16} {latency}", "latency");
+    println!("{:<16} {pacing}", "pacing");
+    println!("{:<16} {stages}", "server");
+
+    if let Some((latency, pacing, stages)) = steady {
+        println!("{:<16} {latency}", "steady latency");
+        println!("{:<16} {pacing}", "steady pacing");
+        println!("{:<16} {stages}", "steady server");
+    }
+
+    println!("{:<16} {corrupted}", "corrupted");
+
+    if let Some(mismatched) = mismatched {
+        println!("{:<16} {mismatched}", "mismatched");
     }
 }
 
-fn client(socket_addr: SocketAddr, width: usize, height: usize) {
-    let stream = hv_sock::Stream::connect(&socket_addr).unwrap();
-    let mut stream = lz4_flex::frame::FrameDecoder::new(stream);
-    let mut buf = vec![0; width * height];
-    let average = Mutex::new(RunningAverage::default());
-    
+fn client(addr: TransportAddr, args: ClientArgs) -> Result<(), BenchError> {
+    let mut stream = transport::connect(&addr)?;
+    let info = info_span!("handshake").in_scope(|| client_handshake(&mut stream))?;
+    info!(%info.compression, info.fps, "connected to server");
+
+    let compressed = AtomicU64::new(0);
+    let decompressed = AtomicU64::new(0);
+    let stream = info.compression
+        .decoder(CountingReader { inner: stream, count: &compressed })
+        .map_err(|error| BenchError::Compression(info.compression, error))?;
+    let mut stream = MessageReader::new(stream, Framing::default());
+    let mut buf = Vec::new();
+
+    // regenerating every frame isn't free, so it's only done when asked for
+    let mut verify = match (args.verify, info.seed) {
+        (true, Some(seed)) => Some((seed, Vec::new())),
+        (true, None) => {
+            warn!("server isn't seeded, frames can't be verified");
+            None
+        },
+        (false, _) => None,
+    };
+    let verifying = verify.is_some();
+    let mismatched = AtomicU64::new(0);
+    let mismatched_count = || verifying.then(|| mismatched.load(Ordering::Relaxed));
+    let latencies = Mutex::new(LatencyRecorder::default());
+    let pacing = Mutex::new(PacingRecorder::default());
+    let mut server_stages = StageRecorder::default();
+    let corrupted = AtomicU64::new(0);
+    let done = AtomicBool::new(false);
+    let connected = Instant::now();
+    // anything too far off for an Instant to hold might as well never come
+    let warmup_ends = connected.checked_add(Duration::from_secs(args.warmup));
+    let deadline = args.duration.and_then(|secs| connected.checked_add(Duration::from_secs(secs.get())));
+    let report = match args.output {
+        Some(format) => {
+            let path = args.output_file.clone()
+                .unwrap_or_else(|| format!("waydows-bench.{}", format.extension()).into());
+            let report = Report::create(&path, format)
+                .map_err(|source| BenchError::Report { path: path.clone(), source })?;
+            info!(path = %path.display(), "writing statistics");
+            Some(Mutex::new(report))
+        },
+        None => None,
+    };
+    let write_record = |kind, latency, pacing| {
+        let Some(report) = &report else { return };
+        let record = Record {
+            kind,
+            elapsed: connected.elapsed(),
+            latency,
+            pacing,
+            corrupted: corrupted.load(Ordering::Relaxed),
+            compression_ratio: compression_ratio(&compressed, &decompressed),
+        };
+
+        if let Err(error) = report.lock().unwrap().write(&record) {
+            warn!(%error, "failed to write statistics");
+        }
+    };
+    let mut warming_up = true;
+    shutdown::install(|| {});
+
+    let mut result = Ok(());
+
     thread::scope(|s| {
         s.spawn(|| loop {
             thread::sleep(Duration::from_secs(1));
-            println!("average: {:?}", average.lock().unwrap().get())
+
+            if done.load(Ordering::Relaxed) {
+                break
+            }
+
+            let latency = latencies.lock().unwrap().take_interval();
+            let pacing = pacing.lock().unwrap().take_interval();
+
+            info!(
+                %latency,
+                %pacing,
+                compression_ratio = ?compression_ratio(&compressed, &decompressed),
+                corrupted = corrupted.load(Ordering::Relaxed),
+                mismatched = ?mismatched_count(),
+                "last second"
+            );
+
+            write_record("interval", latency, pacing);
         });
 
-        loop {
+        let mut received = 0;
+
+        while !shutdown::requested() {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                info!("ran for as long as asked");
+                break
+            }
+
+            if args.frames.is_some_and(|frames| received >= frames.get()) {
+                info!(received, "received as many frames as asked");
+                break
+            }
+
+            let _span = trace_span!("receive_frame").entered();
             let now = Instant::now();
-            stream.read_exact(&mut buf).unwrap();
-            average.lock().unwrap().update(now.elapsed());
+
+            let header = match Frame::read_from(&mut stream, &mut buf) {
+                Ok(Some(header)) => header,
+                Ok(None) => {
+                    info!("server closed the connection");
+                    break
+                },
+                Err(framing::Error::Io(error)) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                    warn!("server closed the connection in the middle of a frame");
+                    pacing.lock().unwrap().record_partial();
+                    break
+                },
+                Err(framing::Error::Checksum) => {
+                    // nothing in the frame can be trusted, its sequence number and timings included
+                    warn!("frame doesn't match its checksum");
+                    corrupted.fetch_add(1, Ordering::Relaxed);
+                    continue
+                },
+                Err(error) => {
+                    result = Err(BenchError::Receive(error));
+                    break
+                },
+            };
+
+            let data = &buf[FrameHeader::LEN..];
+
+            if let Some((seed, expected)) = &mut verify {
+                let _span = trace_span!("verify_frame").entered();
+                let mut rng = payload::seeded_rng(*seed, header.number);
+                expected.resize(data.len(), 0);
+                info.payload.fill(expected, header.width.get(), header.number, &mut rng);
+
+                if data != expected {
+                    warn!(header.sequence, header.number, "frame doesn't match what the seed generates");
+                    mismatched.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            if warming_up && warmup_ends.is_some_and(|warmup_ends| now >= warmup_ends) {
+                info!("warmup over");
+                warming_up = false;
+                latencies.lock().unwrap().end_warmup();
+                pacing.lock().unwrap().end_warmup();
+                server_stages.end_warmup();
+            }
+
+            server_stages.record(&header.timing);
+
+            pacing.lock().unwrap().record(header.sequence, Instant::now(), header.interval);
+            latencies.lock().unwrap().record(now.elapsed());
+            decompressed.fetch_add(data.len() as u64, Ordering::Relaxed);
+            received += 1;
         }
-    })
+
+        done.store(true, Ordering::Relaxed);
+    });
+
+    let latencies = latencies.into_inner().unwrap();
+    let pacing = pacing.into_inner().unwrap();
+
+    info!(
+        latency = %latencies.total(),
+        pacing = %pacing.total(),
+        server_stages = %server_stages.total(),
+        compression_ratio = ?compression_ratio(&compressed, &decompressed),
+        corrupted = corrupted.load(Ordering::Relaxed),
+        mismatched = ?mismatched_count(),
+        "whole run"
+    );
+
+    write_record("whole_run", latencies.total(), pacing.total());
+
+    let steady = match (latencies.steady(), pacing.steady(), server_stages.steady()) {
+        (Some(latency), Some(pacing), Some(stages)) => Some((latency, pacing, stages)),
+        _ => None,
+    };
+
+    match steady {
+        Some((latency, pacing, server_stages)) => {
+            info!(%latency, %pacing, %server_stages, "steady state");
+            write_record("steady_state", latency, pacing);
+        },
+        None => warn!("the run ended before warmup did, there are no steady state statistics"),
+    }
+
+    print_summary(
+        connected.elapsed(),
+        (latencies.total(), pacing.total(), server_stages.total()),
+        steady,
+        corrupted.load(Ordering::Relaxed),
+        mismatched_count(),
+    );
+
+    // the statistics up to the error are still worth having, so it only gets reported after them
+    result
 }
 
-fn server(socket_addr: SocketAddr, width: usize, height: usize, fps: f64) {
-    let listener = hv_sock::Listener::bind(&socket_addr).unwrap();
+/// How long a client gets to send its preamble. Until then it holds a place towards the maximum number of
+/// clients, or one of the threads turning clients away.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
-    thread::scope(|s| {
-        let (screen_sender, screen_receiver) = crossbeam::channel::bounded(fps.round() as usize);
+/// How many clients can be in the middle of being turned away. Any more than that are disconnected without being
+/// told why.
+const MAX_REJECTING: usize = 16;
+
+enum ServerEvent {
+    Accepted(io::Result<(Box<dyn Transport>, PeerAddr)>),
+
+    /// Handshakes get a thread of their own each, so a client that is slow to send its preamble holds up nobody
+    /// else's. One that never sends it gives up its place after [`HANDSHAKE_TIMEOUT`]. Those threads are
+    /// abandoned on shutdown like the one accepting.
+    Handshaken(Span, Result<Box<dyn Transport>, ProtocolError>),
+
+    /// A client that was turned away has been told so, or has timed out.
+    Rejected,
+    Shutdown,
+}
+
+fn server(addr: TransportAddr, frame_len: usize, args: ServerArgs) -> Result<(), BenchError> {
+    let ServerArgs { fps, compression, payload, seed, adaptive_fps, max_clients, bandwidth_limit, .. } = args;
+    let info = StreamInfo { compression, fps, payload, seed };
+    let width = args.screen.width.get();
+    let next_frame_number = &AtomicU64::new(0);
+    let listener = transport::bind(&addr)?;
+    let _socket_file = addr.socket_file();
+    let (event_sender, event_receiver) = crossbeam::channel::unbounded();
+
+    shutdown::install({
+        let event_sender = event_sender.clone();
+        move || {
+            let _ = event_sender.send(ServerEvent::Shutdown);
+        }
+    });
 
+    // accept can't be interrupted, so it gets a thread of its own which is simply abandoned on shutdown
+    thread::spawn({
+        let event_sender = event_sender.clone();
+        move || {
+            let accept = || ServerEvent::Accepted(info_span!("accept").in_scope(|| listener.accept()));
+            while event_sender.send(accept()).is_ok() {}
+        }
+    });
+
+    let workers = &Workers::new(&args.workers);
+    let generators = workers.count();
+    let frame_interval = Duration::from_secs_f64(1.0 / fps);
+
+    // one frame being generated on every generator, plus one waiting and one being sent for a handful of
+    // clients, anything beyond that is allocated and freed as before
+    let buffers = &BufferPool::new(frame_len, generators + 16);
+
+    // every client gets the newest frame generated for it, never a backlog of stale ones
+    let slots: &Slots<Frame> = &Slots::default();
+
+    thread::scope(|s| {
         let mut thread_rng = rand::thread_rng();
-        (0..thread::available_parallelism().unwrap().get())
+        (0..generators)
             .map(|num| (num, SmallRng::from_rng(&mut thread_rng).unwrap()))
             .for_each(|(num, mut rng)| {
-                let screen_sender = screen_sender.clone();
                 s.spawn(move || {
-                    loop {
-                        screen_sender.send(screen(width, height, num, &mut rng)).unwrap()
+                    workers.pin(num);
+
+                    // a slot whose frame has been waiting for as long as its client takes between frames gets a
+                    // fresh one, so a client that stalls gets something recent once it recovers. Without clients
+                    // this blocks until one comes along, and stops once the server shuts down.
+                    while let Some(slot) = slots.wait_for_wanted() {
+                        let frame_number = next_frame_number.fetch_add(1, Ordering::Relaxed);
+                        let buf = buffers.take();
+                        let frame = match seed {
+                            Some(seed) => {
+                                let mut rng = payload::seeded_rng(seed, frame_number);
+                                screen(buf, width, payload, frame_number, num, &mut rng)
+                            },
+                            None => screen(buf, width, payload, frame_number, num, &mut rng),
+                        };
+
+                        if let Some(stale) = slot.publish(frame) {
+                            buffers.recycle(stale.data);
+                        }
                     }
                 });
             });
 
-        println!("listening for incoming streams");
+        info!("listening for incoming streams");
+
+        // clients still in the middle of their handshake count towards the maximum too
+        let mut handshaking = 0;
+        let mut rejecting = 0;
 
         loop {
-            let (stream, addr) = listener.accept().unwrap();
-            let screen_receiver = screen_receiver.clone();
-            println!("new client {stream:?} {addr:?}");
+            let (stream, span) = match event_receiver.recv().unwrap() {
+                ServerEvent::Accepted(Ok((mut stream, addr))) => {
+                    let span = info_span!("client", ?addr);
+                    info!(parent: &span, ?stream, "new client");
+
+                    if max_clients.is_some_and(|max_clients| slots.len() + handshaking >= max_clients.get()) {
+                        if rejecting == MAX_REJECTING {
+                            warn!(parent: &span, max_clients, "dropping client, there are too many already");
+                            continue
+                        }
+
+                        warn!(parent: &span, max_clients, "turning client away, there are too many already");
+                        rejecting += 1;
+
+                        // waits for the client's preamble, which it may never send
+                        let event_sender = event_sender.clone();
+                        thread::spawn(move || {
+                            let result = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+                                .and_then(|()| reject_client(&mut stream));
+
+                            if let Err(error) = result {
+                                warn!(parent: &span, %error, "failed to tell client it was turned away");
+                            }
+
+                            let _ = event_sender.send(ServerEvent::Rejected);
+                        });
+
+                        continue
+                    }
+
+                    handshaking += 1;
+
+                    let event_sender = event_sender.clone();
+                    thread::spawn(move || {
+                        let result = info_span!(parent: &span, "handshake").in_scope(|| {
+                            stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+                            server_handshake(&mut stream, &info)?;
+                            stream.set_read_timeout(None)?;
+                            Ok(stream)
+                        });
+                        let _ = event_sender.send(ServerEvent::Handshaken(span, result));
+                    });
+
+                    continue
+                },
+                ServerEvent::Accepted(Err(error)) => {
+                    warn!(%error, "failed to accept a connection");
+                    continue
+                },
+                ServerEvent::Rejected => {
+                    rejecting -= 1;
+                    continue
+                },
+                ServerEvent::Handshaken(span, result) => {
+                    handshaking -= 1;
+
+                    match result {
+                        Ok(stream) => (stream, span),
+                        Err(error) => {
+                            warn!(parent: &span, %error, "handshake failed");
+                            continue
+                        },
+                    }
+                },
+                ServerEvent::Shutdown => break,
+            };
+
+            // limited below the encoder, so it's the compressed bytes that count
+            let stream: Box<dyn Write + Send> = match bandwidth_limit {
+                Some(mbps) => Box::new(RateLimitedWriter::new(stream, mbps * 1e6 / 8.0)),
+                None => Box::new(stream),
+            };
+
+            let mut stream = match compression.encoder(stream) {
+                Ok(stream) => MessageWriter::new(stream, Framing::default()),
+                Err(error) => {
+                    warn!(parent: &span, %error, "failed to set up compression");
+                    continue
+                },
+            };
+            let slot = slots.add(frame_interval);
 
-            let mut stream = lz4_flex::frame::FrameEncoder::new(stream);
             s.spawn(move || {
-                run_every_second(fps, move || {
-                    match stream.write_all(&screen_receiver.recv().unwrap()) {
-                        Ok(()) => ControlFlow::Continue(()),
-                        Err(_) => ControlFlow::Break(()),
+                let _span = span.entered();
+                let mut previous_send = None;
+                let mut rate = adaptive_fps.then(|| AdaptiveRate::new(fps));
+                let mut closing = false;
+
+                run_every_second(|| {
+                    let Some((sequence, screen)) = slot.take() else {
+                        closing = true;
+                        return ControlFlow::Break(())
+                    };
+
+                    // the next frame goes out no sooner than this one's interval, and no sooner than the last one
+                    // took to write over a limited link, so a fresh frame before then would only go to waste
+                    let interval = Duration::from_secs_f64(1.0 / rate.as_ref().map_or(fps, AdaptiveRate::current));
+                    slot.set_max_age(previous_send.map_or(interval, |previous_send| interval.max(previous_send)));
+                    slots.taken();
+
+                    let _span = trace_span!("send_frame", sequence, len = screen.data.len()).entered();
+
+                    // flush every frame, otherwise the tail of it sits in the encoder until the next one
+                    let started = Instant::now();
+                    let result = trace_span!("encode")
+                        .in_scope(|| screen.write_to(&mut stream, sequence, interval, previous_send))
+                        .and_then(|()| Ok(trace_span!("flush").in_scope(|| stream.flush())?));
+                    let write_time = started.elapsed();
+                    buffers.recycle(screen.data);
+
+                    match result {
+                        Ok(()) => {
+                            previous_send = Some(write_time);
+
+                            let Some(rate) = &mut rate else {
+                                return ControlFlow::Continue(fps)
+                            };
+
+                            let previous = rate.current();
+                            let current = rate.record(write_time);
+
+                            if current != previous {
+                                debug!(fps = current, ?write_time, "adjusted frame rate");
+                            }
+
+                            ControlFlow::Continue(current)
+                        },
+                        Err(framing::Error::Io(error)) => {
+                            info!(%error, "client went away");
+                            ControlFlow::Break(())
+                        },
+                        Err(error) => {
+                            error!(%error, "failed to send frame");
+                            ControlFlow::Break(())
+                        },
+                    }
+                });
+
+                if closing {
+                    info!("closing connection");
+
+                    // otherwise the client can't tell this apart from the connection dropping mid-frame
+                    if let Err(error) = stream.into_inner().finish().and_then(|mut stream| stream.flush()) {
+                        info!(%error, "client went away");
                     }
-                })
+                }
+
+                if let Some(screen) = slots.remove(&slot) {
+                    buffers.recycle(screen.data);
+                }
             });
         }
-    })
+
+        slots.close();
+    });
+
+    Ok(())
 }
 
-#[cfg(target_os = "linux")]
-fn socket_addr(args: &mut Skip<env::Args>) -> SocketAddr {
-    let port = args.next().unwrap().parse().unwrap();
-    SocketAddr::new(port)
+/// Logs `error` along with everything that led to it, and exits.
+fn exit_with(error: &dyn error::Error) -> ! {
+    let mut message = error.to_string();
+    let mut source = error.source();
+
+    while let Some(error) = source {
+        message += &format!(": {error}");
+        source = error.source();
+    }
+
+    error!("{message}");
+    process::exit(1)
 }
 
-#[cfg(windows)]
-fn socket_addr(args: &mut Skip<env::Args>) -> SocketAddr {
-    let vm_id = args.next().unwrap().parse().unwrap();
-    let service_id = args.next().unwrap().parse().unwrap();
-    
-    SocketAddr::new(vm_id, service_id)
+fn transport_addr(addr: &cli::AddrArgs, matches: &ArgMatches) -> TransportAddr {
+    addr.addr(matches).unwrap_or_else(|error| Cli::command().error(ErrorKind::ValueValidation, error).exit())
 }
 
 fn main() {
-    let mut args = env::args().skip(1);
-    let kind = args.next().unwrap();
-    let socket_addr = socket_addr(&mut args);
-    let width = args.next().unwrap().parse().unwrap();
-    let height = args.next().unwrap().parse().unwrap();
-    let fps = args.next().unwrap().parse().unwrap();
-
-    if kind == "client" {
-        client(socket_addr, width, height);
-    } else if kind == "server" {
-        server(socket_addr, width, height, fps);
-    } else {
-        eprintln!("unknown kind {kind}");
-        std::process::exit(1);
-    }
-}
\ No newline at end of file
+    let filter = EnvFilter::try_from_env("WAYDOWS_LOG")
+        .or_else(|_| EnvFilter::try_from_default_env())
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::registry().with(tracing_subscriber::fmt::layer().with_filter(filter));
+
+    // spans are what show up as zones, so the profiler gets all of them regardless of what's being logged
+    #[cfg(feature = "profiling")]
+    let subscriber = subscriber.with(tracing_tracy::TracyLayer::default());
+
+    subscriber.init();
+
+    let matches = Cli::command().get_matches();
+    let Cli { command } = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+
+    // the address arguments belong to whichever subcommand is innermost, and so does where they came from
+    let mut matches = &matches;
+
+    while let Some((_, subcommand)) = matches.subcommand() {
+        matches = subcommand;
+    }
+
+    let result: Result<(), Box<dyn error::Error>> = match command {
+        Command::Bench(BenchCommand::Server(args)) => {
+            let frame_len = args.screen.frame_len()
+                .unwrap_or_else(|error| Cli::command().error(ErrorKind::ValueValidation, error).exit());
+
+            server(transport_addr(&args.addr, matches), frame_len, args).map_err(Box::from)
+        },
+        Command::Bench(BenchCommand::Client(args)) => {
+            client(transport_addr(&args.addr, matches), args).map_err(Box::from)
+        },
+        Command::Bench(BenchCommand::Throughput(ThroughputCommand::Server(args))) => {
+            throughput::server(transport_addr(&args.addr, matches)).map_err(Box::from)
+        },
+        Command::Bench(BenchCommand::Throughput(ThroughputCommand::Client(args))) => {
+            throughput::client(transport_addr(&args.addr, matches), args).map_err(Box::from)
+        },
+        Command::Bench(BenchCommand::Compare(args)) => {
+            if cli::AddrArgs::transport_given(matches) {
+                let error = "compare runs over TCP and a Unix socket by itself, it only takes an hv-sock address";
+                Cli::command().error(ErrorKind::ArgumentConflict, error).exit()
+            }
+
+            // WAYDOWS_TRANSPORT is meant for the other subcommands, so it's ignored here rather than refused
+            let socket_addr = args.addr.socket_addr()
+                .unwrap_or_else(|error| Cli::command().error(ErrorKind::ValueValidation, error).exit());
+
+            compare::compare(socket_addr, args);
+            Ok(())
+        },
+    };
+
+    if let Err(error) = result {
+        exit_with(&*error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use waydows_unix_socket::UnixStream;
+    use super::*;
+    use crate::chaos::Chaos;
+
+    const INFO: StreamInfo = StreamInfo {
+        compression: Compression::Zstd,
+        fps: 60.0,
+        payload: Payload::Counter,
+        seed: Some(3),
+    };
+
+    #[test]
+    fn handshake_passes_on_what_the_server_sends() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || server_handshake(&mut server, &INFO));
+        let info = client_handshake(&mut client).unwrap();
+        server.join().unwrap().unwrap();
+
+        assert_eq!((info.compression, info.fps), (Compression::Zstd, 60.0));
+        assert_eq!((info.payload, info.seed), (Payload::Counter, Some(3)));
+    }
+
+    #[test]
+    fn handshake_refuses_peers_speaking_something_else() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        server.write_all(b"SSH-2.0").unwrap();
+        assert!(matches!(client_handshake(&mut client), Err(ProtocolError::NotWaydows("server"))));
+
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        Preamble { version: PROTOCOL_VERSION + 1, capabilities: 0 }.write_to(&mut client).unwrap();
+        assert!(matches!(
+            server_handshake(&mut server, &INFO),
+            Err(ProtocolError::Version { peer: "client", version }) if version == PROTOCOL_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn handshake_refuses_frame_rates_that_arent_one() {
+        for fps in [0.0, -60.0, f64::NAN, f64::MIN_POSITIVE] {
+            let (mut client, mut server) = UnixStream::pair().unwrap();
+            let server = thread::spawn(move || server_handshake(&mut server, &StreamInfo { fps, ..INFO }));
+            assert!(matches!(client_handshake(&mut client), Err(ProtocolError::InvalidFps(_))));
+            server.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn handshake_reports_a_full_server() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || reject_client(&mut server));
+        assert!(matches!(client_handshake(&mut client), Err(ProtocolError::ServerFull)));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn handshake_gives_up_on_silent_clients() {
+        let (_client, mut server) = UnixStream::pair().unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        assert!(matches!(server_handshake(&mut server, &INFO), Err(ProtocolError::Io(_))));
+        assert!(reject_client(&mut server).is_err());
+    }
+
+    #[test]
+    fn handshake_refuses_clients_that_cant_decode_the_compression() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        Preamble::new(Compression::None.flag() | Compression::Lz4.flag()).write_to(&mut client).unwrap();
+        assert!(matches!(
+            server_handshake(&mut server, &INFO),
+            Err(ProtocolError::UnsupportedCompression(Compression::Zstd))
+        ));
+    }
+
+    #[test]
+    fn frames_survive_fragmented_streams() {
+        let frame = Frame {
+            number: 7,
+            width: 16,
+            data: (0..=255).collect(),
+            generate: Duration::from_millis(2),
+            generated: Instant::now(),
+        };
+        let mut wire = Vec::new();
+        let mut writer = MessageWriter::new(Chaos::new(&mut wire), Framing::default());
+        frame.write_to(&mut writer, 3, Duration::from_millis(16), Some(Duration::from_millis(5))).unwrap();
+        frame.write_to(&mut writer, 4, Duration::from_millis(16), None).unwrap();
+
+        // the second frame's width, which is zero after this and would fail the read if it were looked at
+        let second = wire.len() / 2;
+        wire[second + 8 + 16] ^= 16;
+
+        let mut reader = MessageReader::new(Chaos::new(&wire[..]), Framing::default());
+        let mut buf = Vec::new();
+        let header = Frame::read_from(&mut reader, &mut buf).unwrap().unwrap();
+
+        assert_eq!((header.sequence, header.number, header.width.get()), (3, 7, 16));
+        assert_eq!(header.interval, Duration::from_millis(16));
+        assert_eq!(header.timing.generate, Duration::from_millis(2));
+        assert_eq!(header.timing.previous_send, Some(Duration::from_millis(5)));
+        assert_eq!(buf[FrameHeader::LEN..], frame.data);
+        assert!(matches!(Frame::read_from(&mut reader, &mut buf), Err(framing::Error::Checksum)));
+        assert!(Frame::read_from(&mut reader, &mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn compressed_streams_end_cleanly() {
+        let frame = Frame {
+            number: 0,
+            width: 4,
+            data: vec![1; 64],
+            generate: Duration::ZERO,
+            generated: Instant::now(),
+        };
+
+        for compression in Compression::ALL {
+            let mut writer = MessageWriter::new(compression.encoder(Vec::new()).unwrap(), Framing::default());
+            frame.write_to(&mut writer, 0, Duration::from_millis(16), None).unwrap();
+            let wire = writer.into_inner().finish().unwrap();
+
+            let mut reader = MessageReader::new(compression.decoder(&wire[..]).unwrap(), Framing::default());
+            let mut buf = Vec::new();
+            assert!(Frame::read_from(&mut reader, &mut buf).unwrap().is_some(), "{compression}");
+            assert!(Frame::read_from(&mut reader, &mut buf).unwrap().is_none(), "{compression}");
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use clap::ValueEnum;
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+
+/// What the server fills its frames with.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Payload {
+    /// Random bytes, which no compressor can do anything with.
+    #[default]
+    Random,
+
+    /// A little endian u32 counting up across frames, so shifted or reordered data is easy to spot.
+    Counter,
+
+    /// A diagonal gradient that moves a pixel every frame, which compresses very well.
+    Gradient,
+}
+
+impl Payload {
+    pub const ALL: [Self; 3] = [Self::Random, Self::Counter, Self::Gradient];
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+
+    pub fn as_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub fn fill(self, screen: &mut [u8], width: usize, frame_number: u64, rng: &mut impl Rng) {
+        match self {
+            Self::Random => rng.fill_bytes(screen),
+            Self::Counter => {
+                let words_per_frame = screen.len().div_ceil(4) as u64;
+                let start = frame_number.wrapping_mul(words_per_frame) as u32;
+
+                for (i, chunk) in screen.chunks_mut(4).enumerate() {
+                    let word = start.wrapping_add(i as u32).to_le_bytes();
+                    chunk.copy_from_slice(&word[..chunk.len()]);
+                }
+            },
+            Self::Gradient => {
+                for (y, row) in screen.chunks_mut(width).enumerate() {
+                    for (x, byte) in row.iter_mut().enumerate() {
+                        *byte = (x as u64 + y as u64).wrapping_add(frame_number) as u8;
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// The generator a seeded run fills frame `frame_number` from. Every frame gets its own, so the client can
+/// reproduce any frame without having seen the ones before it.
+pub fn seeded_rng(seed: u64, frame_number: u64) -> SmallRng {
+    // spread consecutive frame numbers across the seed space, so neighbouring frames don't get related seeds
+    SmallRng::seed_from_u64(seed ^ frame_number.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
@@ -0,0 +1,51 @@
+//! Hands frame buffers back to the generators once they've been sent, so a steady stream of frames doesn't
+//! turn into a steady stream of large allocations.
+
+use crossbeam::channel::{self, Receiver, Sender};
+
+pub struct BufferPool {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    len: usize,
+}
+
+impl BufferPool {
+    /// A pool of buffers `len` bytes long, holding on to at most `capacity` of them while they're unused.
+    pub fn new(len: usize, capacity: usize) -> Self {
+        let (sender, receiver) = channel::bounded(capacity);
+        Self { sender, receiver, len }
+    }
+
+    /// An unused buffer if there is one, or a freshly allocated one otherwise. Its contents are whatever the
+    /// last frame left behind.
+    pub fn take(&self) -> Vec<u8> {
+        self.receiver.try_recv().unwrap_or_else(|_| vec![0; self.len])
+    }
+
+    /// Makes `buf` available to the next [`take`](Self::take), unless the pool is already full, in which case
+    /// it's freed.
+    pub fn recycle(&self, buf: Vec<u8>) {
+        debug_assert_eq!(buf.len(), self.len);
+        let _ = self.sender.try_send(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recycled_buffers_are_reused_up_to_capacity() {
+        let pool = BufferPool::new(16, 1);
+        let first = pool.take();
+        let second = pool.take();
+        let first_ptr = first.as_ptr();
+
+        pool.recycle(first);
+        pool.recycle(second);
+
+        let reused = pool.take();
+        assert_eq!(reused.as_ptr(), first_ptr);
+        assert_eq!(pool.take().len(), 16);
+    }
+}
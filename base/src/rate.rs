@@ -0,0 +1,71 @@
+//! Slows a client's frame rate down when its link can't keep up, and brings it back up once it can.
+//!
+//! There's no acknowledgement coming back from the client, but a blocking write that takes most of a frame
+//! interval means the socket's send buffer is full, which is the same thing seen from the other end.
+
+use std::time::Duration;
+
+/// Never go below this, a client that can't even manage this much is better off noticing it's being starved.
+pub const MIN_FPS: f64 = 1.0;
+
+/// Writes taking longer than this fraction of the frame interval count as backpressure.
+const CONGESTED: f64 = 0.8;
+
+/// Writes taking less than this fraction of the frame interval leave room to speed up.
+const IDLE: f64 = 0.5;
+
+/// Additive increase, multiplicative decrease, so a congested link is backed off from quickly and probed again
+/// gently.
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveRate {
+    target: f64,
+    current: f64,
+}
+
+impl AdaptiveRate {
+    pub fn new(target: f64) -> Self {
+        Self { target, current: target }
+    }
+
+    pub fn current(&self) -> f64 {
+        self.current
+    }
+
+    /// Adjusts the rate to how long it took to write the last frame, returning the new rate.
+    pub fn record(&mut self, write_time: Duration) -> f64 {
+        let load = write_time.as_secs_f64() * self.current;
+
+        if load > CONGESTED {
+            self.current = (self.current * 0.75).max(MIN_FPS.min(self.target));
+        } else if load < IDLE {
+            self.current = (self.current + self.target / 20.0).min(self.target);
+        }
+
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_under_backpressure_and_recovers() {
+        let mut rate = AdaptiveRate::new(60.0);
+
+        // a write taking a whole 60 fps interval
+        assert_eq!(rate.record(Duration::from_secs_f64(1.0 / 60.0)), 45.0);
+
+        for _ in 0..100 {
+            rate.record(Duration::from_secs(1));
+        }
+
+        assert_eq!(rate.current(), MIN_FPS);
+
+        for _ in 0..100 {
+            rate.record(Duration::ZERO);
+        }
+
+        assert_eq!(rate.current(), 60.0);
+    }
+}
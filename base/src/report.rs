@@ -0,0 +1,167 @@
+//! Writes the benchmark client's statistics to a file, so runs can be graphed and compared by other tooling.
+//!
+//! Every reporting interval becomes one record, followed by one for the whole run and, if warmup ended, one for
+//! the steady state. CSV gets a header row; JSON Lines is one object per line, so a file cut short by the client
+//! being killed is still readable up to there.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+use clap::ValueEnum;
+use crate::stats::{Pacing, Summary};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Jsonl,
+    Csv,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jsonl => "jsonl",
+            Self::Csv => "csv",
+        }
+    }
+}
+
+pub struct Record {
+    /// `interval`, `whole_run` or `steady_state`.
+    pub kind: &'static str,
+
+    /// Time since the client connected.
+    pub elapsed: Duration,
+    pub latency: Summary,
+    pub pacing: Pacing,
+    pub corrupted: u64,
+    pub compression_ratio: Option<f64>,
+}
+
+const COLUMNS: [&str; 13] = [
+    "kind", "elapsed_secs", "frames", "p50_us", "p95_us", "p99_us", "max_us", "jitter_us", "late", "dropped",
+    "partial", "corrupted", "compression_ratio",
+];
+
+impl Record {
+    /// Every column after `kind`, which is the only one that isn't a number.
+    fn numbers(&self) -> [Option<String>; 12] {
+        let micros = |duration: Duration| Some(duration.as_micros().to_string());
+
+        // an interval without frames has no latencies, which isn't the same as all of them being zero
+        let latency = |duration| if self.latency.count == 0 { None } else { micros(duration) };
+
+        [
+            Some(format!("{:.3}", self.elapsed.as_secs_f64())),
+            Some(self.latency.count.to_string()),
+            latency(self.latency.p50),
+            latency(self.latency.p95),
+            latency(self.latency.p99),
+            latency(self.latency.max),
+            self.pacing.jitter().and_then(micros),
+            Some(self.pacing.late.to_string()),
+            Some(self.pacing.dropped.to_string()),
+            Some(self.pacing.partial.to_string()),
+            Some(self.corrupted.to_string()),
+            self.compression_ratio.map(|ratio| format!("{ratio:.3}")),
+        ]
+    }
+}
+
+pub struct Report<W> {
+    out: W,
+    format: Format,
+}
+
+impl Report<BufWriter<File>> {
+    pub fn create(path: &Path, format: Format) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), format)
+    }
+}
+
+impl<W: Write> Report<W> {
+    pub fn new(mut out: W, format: Format) -> io::Result<Self> {
+        if format == Format::Csv {
+            writeln!(out, "{}", COLUMNS.join(","))?;
+        }
+
+        Ok(Self { out, format })
+    }
+
+    /// Writes and flushes a record, so whatever made it to the file survives the client being killed.
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let numbers = record.numbers();
+
+        match self.format {
+            Format::Csv => {
+                write!(self.out, "{}", record.kind)?;
+
+                for number in &numbers {
+                    write!(self.out, ",{}", number.as_deref().unwrap_or(""))?;
+                }
+            },
+            Format::Jsonl => {
+                write!(self.out, "{{\"{}\":\"{}\"", COLUMNS[0], record.kind)?;
+
+                for (column, number) in COLUMNS[1..].iter().zip(&numbers) {
+                    write!(self.out, ",\"{column}\":{}", number.as_deref().unwrap_or("null"))?;
+                }
+
+                write!(self.out, "}}")?;
+            },
+        }
+
+        writeln!(self.out)?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> Record {
+        Record {
+            kind: "interval",
+            elapsed: Duration::from_millis(1500),
+            latency: Summary {
+                count: 60,
+                p50: Duration::from_micros(100),
+                p95: Duration::from_micros(200),
+                p99: Duration::from_micros(300),
+                max: Duration::from_micros(400),
+            },
+            pacing: Pacing::default(),
+            corrupted: 0,
+            compression_ratio: None,
+        }
+    }
+
+    fn render(format: Format, record: &Record) -> String {
+        let mut report = Report::new(Vec::new(), format).unwrap();
+        report.write(record).unwrap();
+        String::from_utf8(report.out).unwrap()
+    }
+
+    #[test]
+    fn missing_values_are_empty_in_csv_and_null_in_json() {
+        assert_eq!(
+            render(Format::Csv, &record()),
+            "kind,elapsed_secs,frames,p50_us,p95_us,p99_us,max_us,jitter_us,late,dropped,partial,corrupted,compression_ratio\n\
+             interval,1.500,60,100,200,300,400,,0,0,0,0,\n",
+        );
+
+        assert_eq!(
+            render(Format::Jsonl, &record()),
+            "{\"kind\":\"interval\",\"elapsed_secs\":1.500,\"frames\":60,\"p50_us\":100,\"p95_us\":200,\"p99_us\":300,\
+             \"max_us\":400,\"jitter_us\":null,\"late\":0,\"dropped\":0,\"partial\":0,\"corrupted\":0,\
+             \"compression_ratio\":null}\n",
+        );
+    }
+
+    #[test]
+    fn intervals_without_frames_have_no_latencies() {
+        let record = Record { latency: Summary { count: 0, ..record().latency }, ..record() };
+        assert!(render(Format::Csv, &record).ends_with("interval,1.500,0,,,,,,0,0,0,0,\n"));
+    }
+}
@@ -0,0 +1,26 @@
+//! Ctrl+C and SIGTERM handling.
+//!
+//! The first signal only raises a flag (and runs the callback given to [`install`]) so loops can wind down
+//! on their own terms; a second one exits immediately, in case something is stuck in a blocking call.
+
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::info;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn install(on_shutdown: impl Fn() + Send + 'static) {
+    ctrlc::set_handler(move || {
+        if REQUESTED.swap(true, Ordering::Relaxed) {
+            process::exit(130)
+        }
+
+        info!("shutting down, interrupt again to exit immediately");
+        on_shutdown()
+    })
+    .expect("failed to install the shutdown handler")
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,225 @@
+//! A single-value mailbox where publishing replaces whatever wasn't taken yet, so whoever takes from it always
+//! gets the newest value rather than working through a backlog.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+struct State<T> {
+    value: Option<(T, Instant)>,
+
+    /// How old the value can get before the slot wants a newer one.
+    max_age: Duration,
+
+    /// How many values were ever published, taken or not.
+    published: u64,
+
+    /// Whether a producer is already working on the next value.
+    claimed: bool,
+    closed: bool,
+}
+
+pub struct LatestSlot<T> {
+    state: Mutex<State<T>>,
+    ready: Condvar,
+}
+
+impl<T> LatestSlot<T> {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            state: Mutex::new(State { value: None, max_age, published: 0, claimed: false, closed: false }),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Puts `value` in the slot, handing back the value it replaced if that was never taken.
+    pub fn publish(&self, value: T) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        state.claimed = false;
+        state.published += 1;
+        let replaced = state.value.replace((value, Instant::now()));
+        drop(state);
+        self.ready.notify_one();
+        replaced.map(|(value, _)| value)
+    }
+
+    /// When the slot wants a new value: right away if it's empty, or once the value in it is as old as the slot
+    /// lets it get. Never if it's closed or a producer has already claimed it.
+    pub fn wanted_at(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+
+        match &state.value {
+            _ if state.closed || state.claimed => None,
+            Some((_, published)) => Some(*published + state.max_age),
+            None => Some(Instant::now()),
+        }
+    }
+
+    /// Changes how old the value can get, for when the consumer takes values faster or slower than before.
+    /// Producers already waiting only notice after [`Slots::taken`].
+    pub fn set_max_age(&self, max_age: Duration) {
+        self.state.lock().unwrap().max_age = max_age;
+    }
+
+    /// Waits for a value, or returns `None` once the slot is closed. Values come numbered in the order they were
+    /// published from zero, so the ones that were replaced before they could be taken show up as gaps.
+    pub fn take(&self) -> Option<(u64, T)> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if state.closed {
+                return None
+            }
+
+            if let Some((value, _)) = state.value.take() {
+                return Some((state.published - 1, value))
+            }
+
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Wakes up whoever is waiting in [`take`](Self::take), and makes every later call return `None`. Returns
+    /// the value that was never taken, if any.
+    pub fn close(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.ready.notify_all();
+        state.value.take().map(|(value, _)| value)
+    }
+}
+
+struct Registry<T> {
+    slots: Vec<Arc<LatestSlot<T>>>,
+    closed: bool,
+}
+
+/// Every consumer's slot, for producers to find one that wants a value.
+pub struct Slots<T> {
+    registry: Mutex<Registry<T>>,
+    changed: Condvar,
+}
+
+impl<T> Default for Slots<T> {
+    fn default() -> Self {
+        Self {
+            registry: Mutex::new(Registry { slots: Vec::new(), closed: false }),
+            changed: Condvar::new(),
+        }
+    }
+}
+
+impl<T> Slots<T> {
+    pub fn add(&self, max_age: Duration) -> Arc<LatestSlot<T>> {
+        let slot = Arc::new(LatestSlot::new(max_age));
+        self.registry.lock().unwrap().slots.push(slot.clone());
+        self.changed.notify_all();
+        slot
+    }
+
+    /// Removes `slot` and closes it, returning the value that was never taken, if any.
+    pub fn remove(&self, slot: &Arc<LatestSlot<T>>) -> Option<T> {
+        self.registry.lock().unwrap().slots.retain(|other| !Arc::ptr_eq(other, slot));
+        slot.close()
+    }
+
+    pub fn len(&self) -> usize {
+        self.registry.lock().unwrap().slots.len()
+    }
+
+    /// Wakes producers waiting in [`wait_for_wanted`](Self::wait_for_wanted), for consumers to call after
+    /// taking from their slot, since an empty slot is wanted straight away.
+    pub fn taken(&self) {
+        let _registry = self.registry.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    /// Waits for a slot to want a value, see [`LatestSlot::wanted_at`]. Blocks for as long as there are no slots
+    /// at all, so producers don't spin without consumers. Returns `None` once closed.
+    pub fn wait_for_wanted(&self) -> Option<Arc<LatestSlot<T>>> {
+        let mut registry = self.registry.lock().unwrap();
+
+        loop {
+            if registry.closed {
+                return None
+            }
+
+            let next = registry.slots.iter()
+                .filter_map(|slot| Some((slot.wanted_at()?, slot)))
+                .min_by_key(|(wanted_at, _)| *wanted_at);
+
+            registry = match next {
+                Some((wanted_at, slot)) => match wanted_at.checked_duration_since(Instant::now()) {
+                    None => {
+                        // claimed, so the other producers don't all make a value for the same slot
+                        slot.state.lock().unwrap().claimed = true;
+                        return Some(slot.clone())
+                    },
+                    Some(wait) => self.changed.wait_timeout(registry, wait).unwrap().0,
+                },
+                None => self.changed.wait(registry).unwrap(),
+            };
+        }
+    }
+
+    /// Closes every slot and wakes every producer, which from then on get `None`.
+    pub fn close(&self) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.closed = true;
+
+        for slot in &registry.slots {
+            slot.close();
+        }
+
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newest_value_wins() {
+        let slot = LatestSlot::new(Duration::from_secs(60));
+        assert!(slot.wanted_at().unwrap() <= Instant::now());
+
+        assert_eq!(slot.publish(1), None);
+        assert_eq!(slot.publish(2), Some(1));
+        assert!(slot.wanted_at().unwrap() > Instant::now());
+
+        assert_eq!(slot.take(), Some((1, 2)));
+        assert_eq!(slot.publish(3), None);
+        assert_eq!(slot.close(), Some(3));
+        assert_eq!(slot.take(), None);
+        assert_eq!(slot.wanted_at(), None);
+    }
+
+    #[test]
+    fn producers_get_the_slot_that_wants_a_value_first() {
+        let slots = Slots::default();
+        let fresh = slots.add(Duration::from_secs(60));
+        let empty = slots.add(Duration::from_secs(60));
+        fresh.publish(1);
+
+        let wanted = slots.wait_for_wanted().unwrap();
+        assert!(Arc::ptr_eq(&wanted, &empty));
+        assert_eq!(empty.wanted_at(), None);
+
+        slots.close();
+        assert!(slots.wait_for_wanted().is_none());
+    }
+
+    #[test]
+    fn every_slot_goes_stale_at_its_own_age() {
+        let slots = Slots::default();
+        let slow = slots.add(Duration::from_secs(60));
+        let fast = slots.add(Duration::from_secs(60));
+        slow.publish(1);
+        fast.publish(1);
+        fast.set_max_age(Duration::ZERO);
+
+        let wanted = slots.wait_for_wanted().unwrap();
+        assert!(Arc::ptr_eq(&wanted, &fast));
+        assert!(slow.wanted_at().unwrap() > Instant::now());
+    }
+}
@@ -0,0 +1,312 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+use hdrhistogram::Histogram;
+use crate::ServerTiming;
+
+/// Anything slower than this is clamped to it; a frame taking over a minute is broken either way.
+const HIGHEST_TRACKABLE_MICROS: u64 = 60 * 1_000_000;
+
+/// Records frame latencies for the current reporting interval, for the whole run, and for the part of the run
+/// after warmup.
+pub struct LatencyRecorder {
+    interval: Histogram<u64>,
+    total: Histogram<u64>,
+    steady: Option<Histogram<u64>>,
+}
+
+impl Default for LatencyRecorder {
+    fn default() -> Self {
+        let histogram = Histogram::new_with_bounds(1, HIGHEST_TRACKABLE_MICROS, 3).unwrap();
+
+        Self {
+            interval: histogram.clone(),
+            total: histogram,
+            steady: None,
+        }
+    }
+}
+
+impl LatencyRecorder {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX).max(1);
+        self.interval.saturating_record(micros);
+        self.total.saturating_record(micros);
+
+        if let Some(steady) = &mut self.steady {
+            steady.saturating_record(micros);
+        }
+    }
+
+    /// Starts recording steady state latencies, everything recorded before this only counts towards the total.
+    pub fn end_warmup(&mut self) {
+        let mut steady = self.total.clone();
+        steady.reset();
+        self.steady = Some(steady);
+    }
+
+    /// Summarizes everything recorded since the last call and starts a new interval.
+    pub fn take_interval(&mut self) -> Summary {
+        let summary = Summary::of(&self.interval);
+        self.interval.reset();
+        summary
+    }
+
+    pub fn total(&self) -> Summary {
+        Summary::of(&self.total)
+    }
+
+    /// What was recorded since warmup ended, if it has.
+    pub fn steady(&self) -> Option<Summary> {
+        self.steady.as_ref().map(Summary::of)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Summary {
+    fn of(histogram: &Histogram<u64>) -> Self {
+        let quantile = |quantile| Duration::from_micros(histogram.value_at_quantile(quantile));
+
+        Self {
+            count: histogram.len(),
+            p50: quantile(0.5),
+            p95: quantile(0.95),
+            p99: quantile(0.99),
+            max: Duration::from_micros(histogram.max()),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count == 0 {
+            return f.write_str("no frames")
+        }
+
+        write!(
+            f,
+            "{} frames, p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+            self.count, self.p50, self.p95, self.p99, self.max,
+        )
+    }
+}
+
+/// Records the stages a frame went through on the server before it was sent, as reported in its header.
+#[derive(Default)]
+pub struct StageRecorder {
+    generate: LatencyRecorder,
+    queued: LatencyRecorder,
+    send: LatencyRecorder,
+}
+
+impl StageRecorder {
+    pub fn record(&mut self, timing: &ServerTiming) {
+        self.generate.record(timing.generate);
+        self.queued.record(timing.queued);
+
+        if let Some(send) = timing.previous_send {
+            self.send.record(send);
+        }
+    }
+
+    pub fn end_warmup(&mut self) {
+        self.generate.end_warmup();
+        self.queued.end_warmup();
+        self.send.end_warmup();
+    }
+
+    pub fn total(&self) -> Stages {
+        Stages { generate: self.generate.total(), queued: self.queued.total(), send: self.send.total() }
+    }
+
+    pub fn steady(&self) -> Option<Stages> {
+        Some(Stages { generate: self.generate.steady()?, queued: self.queued.steady()?, send: self.send.steady()? })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stages {
+    pub generate: Summary,
+    pub queued: Summary,
+    pub send: Summary,
+}
+
+impl fmt::Display for Stages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "generate p50 {:?}, queued p50 {:?}, send p50 {:?} (p99 {:?}, {:?}, {:?})",
+            self.generate.p50, self.queued.p50, self.send.p50, self.generate.p99, self.queued.p99, self.send.p99,
+        )
+    }
+}
+
+/// Tracks how evenly frames arrive compared to the rate the server is aiming for, and how many never did.
+#[derive(Default)]
+pub struct PacingRecorder {
+    last_arrival: Option<Instant>,
+    next_sequence: u64,
+    interval: Pacing,
+    total: Pacing,
+    steady: Option<Pacing>,
+}
+
+impl PacingRecorder {
+    fn update(&mut self, f: impl Fn(&mut Pacing)) {
+        f(&mut self.interval);
+        f(&mut self.total);
+
+        if let Some(steady) = &mut self.steady {
+            f(steady);
+        }
+    }
+
+    /// Records a frame arriving, which the server meant to send `target_interval` after the one before it.
+    pub fn record(&mut self, sequence: u64, arrival: Instant, target_interval: Duration) {
+        let dropped = sequence.saturating_sub(self.next_sequence);
+        self.next_sequence = sequence + 1;
+
+        let gap = self.last_arrival.replace(arrival).map(|last_arrival| arrival - last_arrival);
+
+        self.update(|pacing| {
+            pacing.dropped += dropped;
+
+            if let Some(gap) = gap {
+                pacing.deviation += gap.max(target_interval) - gap.min(target_interval);
+                pacing.gaps += 1;
+
+                // half an interval of slack, anything later than that would have missed a display refresh
+                if gap > target_interval * 3 / 2 {
+                    pacing.late += 1;
+                }
+            }
+        })
+    }
+
+    /// A frame the stream ended in the middle of.
+    pub fn record_partial(&mut self) {
+        self.update(|pacing| pacing.partial += 1)
+    }
+
+    pub fn take_interval(&mut self) -> Pacing {
+        std::mem::take(&mut self.interval)
+    }
+
+    pub fn total(&self) -> Pacing {
+        self.total
+    }
+
+    pub fn end_warmup(&mut self) {
+        self.steady = Some(Pacing::default());
+    }
+
+    pub fn steady(&self) -> Option<Pacing> {
+        self.steady
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Pacing {
+    deviation: Duration,
+    gaps: u32,
+    pub late: u64,
+    pub dropped: u64,
+    pub partial: u64,
+}
+
+impl Pacing {
+    /// The mean difference between the time between two frames and the interval the server is aiming for.
+    pub fn jitter(&self) -> Option<Duration> {
+        self.deviation.checked_div(self.gaps)
+    }
+}
+
+impl fmt::Display for Pacing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(jitter) = self.jitter() {
+            write!(f, "jitter {jitter:?}, ")?;
+        }
+
+        write!(f, "{} late, {} dropped, {} partial", self.late, self.dropped, self.partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals_reset_but_total_does_not() {
+        let mut recorder = LatencyRecorder::default();
+
+        for millis in 1..=100 {
+            recorder.record(Duration::from_millis(millis));
+        }
+
+        let interval = recorder.take_interval();
+        assert_eq!(interval.count, 100);
+        assert!(interval.p50 >= Duration::from_millis(49) && interval.p50 <= Duration::from_millis(51));
+        assert!(interval.max >= Duration::from_millis(99));
+
+        recorder.record(Duration::from_millis(5));
+        assert_eq!(recorder.take_interval().count, 1);
+        assert_eq!(recorder.total().count, 101);
+    }
+
+    #[test]
+    fn warmup_only_counts_towards_the_total() {
+        let mut recorder = LatencyRecorder::default();
+        recorder.record(Duration::from_secs(1));
+        assert_eq!(recorder.steady(), None);
+
+        recorder.end_warmup();
+        recorder.record(Duration::from_millis(5));
+
+        let steady = recorder.steady().unwrap();
+        assert_eq!(steady.count, 1);
+        assert!(steady.max < Duration::from_millis(6));
+        assert_eq!(recorder.total().count, 2);
+    }
+
+    #[test]
+    fn the_first_frame_has_no_send_time() {
+        let mut recorder = StageRecorder::default();
+        let timing = |previous_send| ServerTiming {
+            generate: Duration::from_millis(2),
+            queued: Duration::from_millis(1),
+            previous_send,
+        };
+
+        recorder.record(&timing(None));
+        recorder.record(&timing(Some(Duration::from_millis(3))));
+
+        let stages = recorder.total();
+        assert_eq!((stages.generate.count, stages.queued.count, stages.send.count), (2, 2, 1));
+        assert!(stages.send.p50 >= Duration::from_millis(3) && stages.send.p50 < Duration::from_millis(4));
+    }
+
+    #[test]
+    fn pacing_counts_late_and_dropped_frames() {
+        let mut recorder = PacingRecorder::default();
+        let start = Instant::now();
+        let interval = Duration::from_millis(100);
+
+        recorder.record(0, start, interval);
+        recorder.record(1, start + Duration::from_millis(110), interval);
+        recorder.record(4, start + Duration::from_millis(400), interval);
+
+        let pacing = recorder.take_interval();
+        assert_eq!(pacing.late, 1);
+        assert_eq!(pacing.dropped, 2);
+        assert_eq!(pacing.jitter(), Some(Duration::from_millis(100)));
+        assert_eq!(recorder.take_interval(), Pacing::default());
+        assert_eq!(recorder.total().dropped, 2);
+    }
+}
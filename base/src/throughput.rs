@@ -0,0 +1,283 @@
+//! Saturates the link with fixed-size messages, first from the client to the server and then back, and
+//! reports how much made it through in each direction.
+//!
+//! The client opens with a [`Request`]. Every message after that has the same size, so the only thing that
+//! needs marking is the last one of a direction, which is done with its first byte. Once the server has seen
+//! the last upload message it reports what it measured and starts sending.
+//!
+//! Both ends can batch their messages into fewer writes, see [`CoalescingWriter`].
+
+use std::{fmt, io, thread};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{info, info_span, warn};
+use crate::cli::ThroughputClientArgs;
+use crate::coalesce::CoalescingWriter;
+use crate::shutdown;
+use crate::transport::{self, TransportAddr, TransportListener};
+
+/// Keeps a bogus request from making the server allocate whatever it says.
+pub const MAX_MESSAGE_SIZE: u32 = 64 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Transport(#[from] transport::Error),
+
+    #[error("message size {0} is not within 1..={MAX_MESSAGE_SIZE}")]
+    MessageSize(u32),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+const MORE: u8 = 0;
+const LAST: u8 = 1;
+
+pub struct Request {
+    pub message_size: u32,
+    pub duration: Duration,
+
+    /// How long a message may be held back to batch it with the ones after it, if at all.
+    pub coalesce: Option<Duration>,
+}
+
+impl Request {
+    fn write_to(&self, mut stream: impl Write) -> io::Result<()> {
+        let mut buf = [0; 20];
+        buf[..4].copy_from_slice(&self.message_size.to_le_bytes());
+        buf[4..12].copy_from_slice(&(self.duration.as_millis() as u64).to_le_bytes());
+
+        // zero microseconds of coalescing is no coalescing, so zero is as good as none on the wire
+        let coalesce = self.coalesce.map_or(0, |coalesce| coalesce.as_micros().max(1) as u64);
+        buf[12..].copy_from_slice(&coalesce.to_le_bytes());
+        stream.write_all(&buf)
+    }
+
+    fn read_from(mut stream: impl Read) -> Result<Self, Error> {
+        let mut buf = [0; 20];
+        stream.read_exact(&mut buf)?;
+        let message_size = u32::from_le_bytes(buf[..4].try_into().unwrap());
+        let duration = Duration::from_millis(u64::from_le_bytes(buf[4..12].try_into().unwrap()));
+        let coalesce = match u64::from_le_bytes(buf[12..].try_into().unwrap()) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        };
+
+        if message_size == 0 || message_size > MAX_MESSAGE_SIZE {
+            return Err(Error::MessageSize(message_size));
+        }
+
+        Ok(Self { message_size, duration, coalesce })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Throughput {
+    messages: u64,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+impl Throughput {
+    pub fn gbps(&self) -> f64 {
+        self.bytes as f64 * 8.0 / self.elapsed.as_secs_f64() / 1e9
+    }
+
+    pub fn messages_per_sec(&self) -> f64 {
+        self.messages as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// What connections running side by side got through together, over as long as the slowest one took.
+    pub fn combined(throughputs: &[Self]) -> Self {
+        Self {
+            messages: throughputs.iter().map(|throughput| throughput.messages).sum(),
+            bytes: throughputs.iter().map(|throughput| throughput.bytes).sum(),
+            elapsed: throughputs.iter().map(|throughput| throughput.elapsed).max().unwrap_or_default(),
+        }
+    }
+
+    fn write_to(&self, mut stream: impl Write) -> io::Result<()> {
+        let mut buf = [0; 24];
+        buf[..8].copy_from_slice(&self.messages.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.bytes.to_le_bytes());
+        buf[16..].copy_from_slice(&(self.elapsed.as_nanos() as u64).to_le_bytes());
+        stream.write_all(&buf)?;
+        stream.flush()
+    }
+
+    fn read_from(mut stream: impl Read) -> io::Result<Self> {
+        let mut buf = [0; 24];
+        stream.read_exact(&mut buf)?;
+
+        Ok(Self {
+            messages: u64::from_le_bytes(buf[..8].try_into().unwrap()),
+            bytes: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+            elapsed: Duration::from_nanos(u64::from_le_bytes(buf[16..].try_into().unwrap())),
+        })
+    }
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.3} Gbps, {:.0} messages/s ({} messages in {:?})",
+            self.gbps(), self.messages_per_sec(), self.messages, self.elapsed,
+        )
+    }
+}
+
+fn send_for(stream: impl Write, request: &Request) -> io::Result<()> {
+    match request.coalesce {
+        Some(max_delay) => {
+            send_messages_for(CoalescingWriter::new(stream, max_delay), request.message_size, request.duration)
+        },
+        None => send_messages_for(stream, request.message_size, request.duration),
+    }
+}
+
+fn send_messages_for(mut stream: impl Write, message_size: u32, duration: Duration) -> io::Result<()> {
+    let mut message = vec![MORE; message_size as usize];
+    let start = Instant::now();
+
+    while start.elapsed() < duration {
+        stream.write_all(&message)?;
+    }
+
+    message[0] = LAST;
+    stream.write_all(&message)?;
+    stream.flush()
+}
+
+fn receive_until_last(mut stream: impl Read, message_size: u32) -> io::Result<Throughput> {
+    let mut message = vec![0; message_size as usize];
+    let mut messages = 0;
+    let start = Instant::now();
+
+    loop {
+        stream.read_exact(&mut message)?;
+        messages += 1;
+
+        if message[0] == LAST {
+            break
+        }
+    }
+
+    Ok(Throughput { messages, bytes: messages * message_size as u64, elapsed: start.elapsed() })
+}
+
+/// The server's half of a run.
+pub fn serve(mut stream: impl Read + Write) -> Result<(), Error> {
+    let request = Request::read_from(&mut stream)?;
+    info!(
+        message_size = request.message_size,
+        duration = ?request.duration,
+        coalesce = ?request.coalesce,
+        "starting run"
+    );
+
+    let upload = receive_until_last(&mut stream, request.message_size)?;
+    info!(%upload, "client to server");
+    upload.write_to(&mut stream)?;
+
+    Ok(send_for(&mut stream, &request)?)
+}
+
+pub fn server(addr: TransportAddr) -> Result<(), Error> {
+    let listener = transport::bind(&addr)?;
+    let _socket_file = addr.socket_file();
+    let (shutdown_sender, shutdown_receiver) = crossbeam::channel::bounded(1);
+    shutdown::install(move || {
+        let _ = shutdown_sender.try_send(());
+    });
+
+    info!("listening for incoming streams");
+
+    // accept can't be interrupted, so it gets a thread of its own which is simply abandoned on shutdown, along
+    // with any runs still going
+    thread::spawn(move || loop {
+        let (stream, addr) = match info_span!("accept").in_scope(|| listener.accept()) {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                warn!(%error, "failed to accept a connection");
+                continue
+            },
+        };
+        let span = info_span!("client", ?addr);
+
+        // runs sharing the link measure each other, which is the point when a client opens several connections
+        thread::spawn(move || match span.in_scope(|| serve(stream)) {
+            Ok(()) => info!(parent: &span, "run finished"),
+            Err(error) => warn!(parent: &span, %error, "run failed"),
+        });
+    });
+
+    let _ = shutdown_receiver.recv();
+    Ok(())
+}
+
+/// The client's half of a run, returning what got through from the client to the server and back.
+pub fn run(mut stream: impl Read + Write, request: &Request) -> Result<(Throughput, Throughput), Error> {
+    request.write_to(&mut stream)?;
+    send_for(&mut stream, request)?;
+    let upload = Throughput::read_from(&mut stream)?;
+    let download = receive_until_last(&mut stream, request.message_size)?;
+    Ok((upload, download))
+}
+
+pub fn client(addr: TransportAddr, args: ThroughputClientArgs) -> Result<(), Error> {
+    let request = args.run.request();
+    let connections = args.connections.get();
+
+    // every connection is up before any of them starts, so they all send over the same stretch of time
+    let streams = (0..connections)
+        .map(|_| transport::connect(&addr))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    info!(
+        message_size = request.message_size,
+        duration = ?request.duration,
+        coalesce = ?request.coalesce,
+        connections,
+        "starting run"
+    );
+
+    let results = thread::scope(|scope| {
+        let runs: Vec<_> = streams.into_iter().map(|stream| scope.spawn(|| run(stream, &request))).collect();
+        runs.into_iter().map(|run| run.join().unwrap()).collect::<Result<Vec<_>, _>>()
+    })?;
+
+    if let [(upload, download)] = results[..] {
+        info!(%upload, "client to server");
+        info!(%download, "server to client");
+        return Ok(())
+    }
+
+    for (connection, (upload, download)) in results.iter().enumerate() {
+        info!(connection, %upload, %download, "connection finished");
+    }
+
+    let (uploads, downloads): (Vec<_>, Vec<_>) = results.into_iter().unzip();
+    info!(upload = %Throughput::combined(&uploads), "client to server, all connections");
+    info!(download = %Throughput::combined(&downloads), "server to client, all connections");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combined_throughput_is_over_the_slowest_connection() {
+        let fast = Throughput { messages: 10, bytes: 1000, elapsed: Duration::from_secs(1) };
+        let slow = Throughput { messages: 30, bytes: 3000, elapsed: Duration::from_secs(2) };
+
+        let combined = Throughput::combined(&[fast, slow]);
+        assert_eq!(combined.messages, 40);
+        assert_eq!(combined.bytes, 4000);
+        assert_eq!(combined.elapsed, Duration::from_secs(2));
+        assert_eq!(combined.gbps(), 4000.0 * 8.0 / 2.0 / 1e9);
+    }
+}
@@ -0,0 +1,245 @@
+//! The socket types the benchmarks can run over, and picking one of them at runtime from an address like
+//! `hvsock://...`, `tcp://host:port` or `unix:///path`.
+
+use std::{fmt, fs};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use hv_sock::SocketAddr;
+use thiserror::Error;
+use waydows_unix_socket::{UnixListener, UnixStream};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to listen on {addr}")]
+    Bind { addr: String, source: io::Error },
+
+    #[error("failed to connect to {addr}")]
+    Connect { addr: String, source: io::Error },
+}
+
+#[cfg(target_os = "linux")]
+pub fn hv_sock_addr(port: &str) -> Result<SocketAddr, String> {
+    let port = port.parse().map_err(|error| format!("invalid port {port}: {error:?}"))?;
+    Ok(SocketAddr::new(port))
+}
+
+#[cfg(windows)]
+pub fn hv_sock_addr(vm_id: &str, service_id: &str) -> Result<SocketAddr, String> {
+    let vm_id = vm_id.parse().map_err(|error| format!("invalid vm id {vm_id}: {error:?}"))?;
+    let service_id = service_id.parse().map_err(|error| format!("invalid service id {service_id}: {error:?}"))?;
+    Ok(SocketAddr::new(vm_id, service_id))
+}
+
+/// Where to listen or connect, over whichever transport.
+#[derive(Debug)]
+pub enum TransportAddr {
+    HvSock(SocketAddr),
+
+    /// Anything [`TcpListener::bind`] and [`TcpStream::connect`] resolve, so `host:port`.
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for TransportAddr {
+    type Err = String;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = addr.split_once("://")
+            .ok_or_else(|| format!("{addr} doesn't start with hvsock://, tcp:// or unix://"))?;
+
+        match scheme {
+            #[cfg(target_os = "linux")]
+            "hvsock" => hv_sock_addr(rest).map(Self::HvSock),
+
+            #[cfg(windows)]
+            "hvsock" => {
+                let (vm_id, service_id) = rest.split_once('/')
+                    .ok_or_else(|| format!("{addr} isn't hvsock://VM_ID/SERVICE_ID"))?;
+
+                hv_sock_addr(vm_id, service_id).map(Self::HvSock)
+            },
+
+            "tcp" if !rest.is_empty() => Ok(Self::Tcp(rest.to_owned())),
+            "unix" if !rest.is_empty() => Ok(Self::Unix(rest.into())),
+            "tcp" | "unix" => Err(format!("{addr} is missing the address after the scheme")),
+            _ => Err(format!("unknown transport {scheme}, expected hvsock, tcp or unix")),
+        }
+    }
+}
+
+impl fmt::Display for TransportAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HvSock(addr) => write!(f, "hvsock://{addr:?}"),
+            Self::Tcp(addr) => write!(f, "tcp://{addr}"),
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+impl TransportAddr {
+    /// What's left behind by listening on this address, which is the socket's file for a Unix socket.
+    pub fn socket_file(&self) -> Option<SocketFile> {
+        match self {
+            Self::Unix(path) => Some(SocketFile(path.clone())),
+            Self::HvSock(_) | Self::Tcp(_) => None,
+        }
+    }
+}
+
+/// Removes a Unix socket's file when dropped. Closing the listener doesn't, and binding to the path again fails
+/// for as long as the file is there.
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+pub fn bind_hv_sock(addr: &SocketAddr) -> Result<hv_sock::Listener, Error> {
+    hv_sock::Listener::bind(addr).map_err(|source| Error::Bind { addr: format!("{addr:?}"), source })
+}
+
+pub fn bind(addr: &TransportAddr) -> Result<Listener, Error> {
+    let listener = match addr {
+        TransportAddr::HvSock(addr) => return bind_hv_sock(addr).map(Listener::HvSock),
+        TransportAddr::Tcp(addr) => TcpListener::bind(addr).map(Listener::Tcp),
+        TransportAddr::Unix(path) => UnixListener::bind(path).map(Listener::Unix),
+    };
+
+    listener.map_err(|source| Error::Bind { addr: addr.to_string(), source })
+}
+
+pub fn connect(addr: &TransportAddr) -> Result<Box<dyn Transport>, Error> {
+    let stream = match addr {
+        TransportAddr::HvSock(addr) => hv_sock::Stream::connect(addr).map(boxed),
+        TransportAddr::Tcp(addr) => TcpStream::connect(addr).and_then(|stream| {
+            stream.set_nodelay(true)?;
+            Ok(boxed(stream))
+        }),
+        TransportAddr::Unix(path) => UnixStream::connect(path).map(boxed),
+    };
+
+    stream.map_err(|source| Error::Connect { addr: addr.to_string(), source })
+}
+
+/// A connected, bidirectional byte stream.
+pub trait Transport: Read + Write + Send + fmt::Debug + 'static {
+    /// Makes reads that wait longer than `timeout` fail, or lets them wait forever if it's `None`.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Transport for hv_sock::Stream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        hv_sock::Stream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Transport for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Transport for Box<dyn Transport> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+fn boxed(stream: impl Transport) -> Box<dyn Transport> {
+    Box::new(stream)
+}
+
+pub trait TransportListener: Send + 'static {
+    type Stream: Transport;
+    type Addr: fmt::Debug + Send;
+
+    fn accept(&self) -> io::Result<(Self::Stream, Self::Addr)>;
+}
+
+impl TransportListener for hv_sock::Listener {
+    type Stream = hv_sock::Stream;
+    type Addr = hv_sock::SocketAddr;
+
+    fn accept(&self) -> io::Result<(Self::Stream, Self::Addr)> {
+        hv_sock::Listener::accept(self)
+    }
+}
+
+impl TransportListener for TcpListener {
+    type Stream = TcpStream;
+    type Addr = std::net::SocketAddr;
+
+    fn accept(&self) -> io::Result<(Self::Stream, Self::Addr)> {
+        let (stream, addr) = TcpListener::accept(self)?;
+
+        // frames and messages are written whole, so Nagle only ever delays the tail of them
+        stream.set_nodelay(true)?;
+        Ok((stream, addr))
+    }
+}
+
+impl TransportListener for UnixListener {
+    type Stream = UnixStream;
+    type Addr = waydows_unix_socket::SocketAddr;
+
+    fn accept(&self) -> io::Result<(Self::Stream, Self::Addr)> {
+        UnixListener::accept(self)
+    }
+}
+
+/// Whoever connected to a [`Listener`], which is only good for logging.
+pub type PeerAddr = Box<dyn fmt::Debug + Send>;
+
+/// A listener for whichever transport a [`TransportAddr`] names.
+pub enum Listener {
+    HvSock(hv_sock::Listener),
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl TransportListener for Listener {
+    type Stream = Box<dyn Transport>;
+    type Addr = PeerAddr;
+
+    fn accept(&self) -> io::Result<(Self::Stream, Self::Addr)> {
+        fn accept_boxed<L: TransportListener>(listener: &L) -> io::Result<(Box<dyn Transport>, PeerAddr)> {
+            let (stream, addr) = listener.accept()?;
+            Ok((boxed(stream), Box::new(addr)))
+        }
+
+        match self {
+            Self::HvSock(listener) => accept_boxed(listener),
+            Self::Tcp(listener) => accept_boxed(listener),
+            Self::Unix(listener) => accept_boxed(listener),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_pick_their_transport_by_scheme() {
+        assert!(matches!("tcp://localhost:5000".parse(), Ok(TransportAddr::Tcp(addr)) if addr == "localhost:5000"));
+        assert!(matches!(
+            "unix:///tmp/waydows.sock".parse(),
+            Ok(TransportAddr::Unix(path)) if path.to_str() == Some("/tmp/waydows.sock")
+        ));
+        assert!("tcp://".parse::<TransportAddr>().is_err());
+        assert!("udp://localhost:5000".parse::<TransportAddr>().is_err());
+        assert!("localhost:5000".parse::<TransportAddr>().is_err());
+    }
+}
@@ -0,0 +1,52 @@
+//! The threads the benchmark server generates frames on.
+
+use std::thread;
+use core_affinity::CoreId;
+use tracing::warn;
+use crate::cli::WorkerArgs;
+
+pub struct Workers {
+    count: usize,
+
+    /// The cores to pin workers to, if they should be pinned at all.
+    cores: Option<Vec<CoreId>>,
+}
+
+impl Workers {
+    pub fn new(args: &WorkerArgs) -> Self {
+        let count = match args.workers.map_or_else(thread::available_parallelism, Ok) {
+            Ok(count) => count.get(),
+            Err(error) => {
+                warn!(%error, "can't tell how many cores there are, generating frames on one thread");
+                1
+            },
+        };
+
+        let cores = args.pin_workers.then(core_affinity::get_core_ids).and_then(|cores| {
+            let cores = cores.filter(|cores| !cores.is_empty());
+
+            if cores.is_none() {
+                warn!("can't tell which cores there are, leaving workers unpinned");
+            }
+
+            cores
+        });
+
+        Self { count, cores }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Pins the calling thread to the core worker `num` belongs on, going round the cores if there are more
+    /// workers than them. Does nothing unless pinning was asked for.
+    pub fn pin(&self, num: usize) {
+        let Some(cores) = &self.cores else { return };
+        let core = cores[num % cores.len()];
+
+        if !core_affinity::set_for_current(core) {
+            warn!(core = core.id, "failed to pin worker");
+        }
+    }
+}
@@ -121,7 +121,7 @@ mod unix_listener {
     use crate::{Incoming, SocketAddr, uds_impl, UnixStream};
 
     #[derive(Debug)]
-    pub struct UnixListener(uds_impl::UnixListener);
+    pub struct UnixListener(pub(crate) uds_impl::UnixListener);
 
     impl UnixListener {
         pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
@@ -202,7 +202,410 @@ mod incoming {
     }
 }
 
+#[cfg(unix)]
+mod ancillary {
+    use std::{io, mem, ptr};
+    use std::io::{IoSlice, IoSliceMut};
+    use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+    use crate::UnixStream;
+
+    /// The most file descriptors a single message can carry, which is Linux's `SCM_MAX_FD`.
+    pub const MAX_FDS: usize = 253;
+
+    // received descriptors shouldn't leak into whatever gets spawned next, elsewhere set_cloexec sees to that
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const RECV_FLAGS: libc::c_int = 0;
+
+    fn control_len(fds: usize) -> usize {
+        unsafe { libc::CMSG_SPACE((fds * mem::size_of::<RawFd>()) as _) as usize }
+    }
+
+    /// Zeroed room for `len` bytes of control messages, aligned for the headers that get written into it.
+    fn control_buf(len: usize) -> Vec<libc::cmsghdr> {
+        vec![unsafe { mem::zeroed() }; len.div_ceil(mem::size_of::<libc::cmsghdr>())]
+    }
+
+    /// What [`RECV_FLAGS`] does on Linux, done by hand once the descriptors are already open. Something spawned
+    /// from another thread in between can still inherit them.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn set_cloexec(fd: &OwnedFd) -> io::Result<()> {
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+
+        if flags == -1 || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error())
+        }
+
+        Ok(())
+    }
+
+    impl UnixStream {
+        /// Writes `bufs` like [`write_vectored`](std::io::Write::write_vectored) does, sending `fds` along with
+        /// them. The receiver gets its own duplicates of the descriptors, which arrive with the first byte
+        /// written, so sending descriptors without any bytes fails.
+        pub fn send_with_fds(&self, bufs: &[IoSlice<'_>], fds: &[BorrowedFd<'_>]) -> io::Result<usize> {
+            if fds.len() > MAX_FDS {
+                let error = format!("can't send more than {MAX_FDS} file descriptors at once");
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, error))
+            }
+
+            // a stream socket sends nothing at all for an empty write, descriptors included
+            if !fds.is_empty() && bufs.iter().all(|buf| buf.is_empty()) {
+                let error = "file descriptors can only be sent along with at least one byte";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, error))
+            }
+
+            let fds_len = mem::size_of_val(fds);
+            let mut control = control_buf(if fds.is_empty() { 0 } else { control_len(fds.len()) });
+
+            // IoSlice is guaranteed to be ABI compatible with iovec
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_iov = bufs.as_ptr() as *mut libc::iovec;
+            msg.msg_iovlen = bufs.len() as _;
+
+            if !fds.is_empty() {
+                msg.msg_control = control.as_mut_ptr().cast();
+                msg.msg_controllen = control_len(fds.len()) as _;
+
+                unsafe {
+                    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                    (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                    (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as _) as _;
+
+                    // BorrowedFd is a transparent wrapper around the raw descriptor
+                    ptr::copy_nonoverlapping(fds.as_ptr().cast::<u8>(), libc::CMSG_DATA(cmsg), fds_len);
+                }
+            }
+
+            match unsafe { libc::sendmsg(self.0.as_raw_fd(), &msg, 0) } {
+                -1 => Err(io::Error::last_os_error()),
+                sent => Ok(sent as usize),
+            }
+        }
+
+        /// Reads into `bufs` like [`read_vectored`](std::io::Read::read_vectored) does, appending whatever file
+        /// descriptors came along to `fds`.
+        ///
+        /// Fails if more descriptors were sent than [`MAX_FDS`], in which case the kernel drops the rest. The
+        /// data is read regardless, so the stream is out of step with the sender after that and best closed.
+        pub fn recv_with_fds(&self, bufs: &mut [IoSliceMut<'_>], fds: &mut Vec<OwnedFd>) -> io::Result<usize> {
+            let mut control = control_buf(control_len(MAX_FDS));
+
+            // IoSliceMut is guaranteed to be ABI compatible with iovec
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_iov = bufs.as_mut_ptr().cast();
+            msg.msg_iovlen = bufs.len() as _;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = mem::size_of_val(&control[..]) as _;
+
+            let received = match unsafe { libc::recvmsg(self.0.as_raw_fd(), &mut msg, RECV_FLAGS) } {
+                -1 => return Err(io::Error::last_os_error()),
+                received => received as usize,
+            };
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            let first_fd = fds.len();
+
+            unsafe {
+                let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+
+                while !cmsg.is_null() {
+                    if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                        let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                        let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+
+                        // the kernel opened these for this process, nothing else owns them
+                        for i in 0..len / mem::size_of::<RawFd>() {
+                            fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                        }
+                    }
+
+                    cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                }
+            }
+
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            fds[first_fd..].iter().try_for_each(set_cloexec)?;
+
+            if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+                let error = format!("more than {MAX_FDS} file descriptors were sent, the rest were dropped");
+                return Err(io::Error::new(io::ErrorKind::InvalidData, error))
+            }
+
+            Ok(received)
+        }
+    }
+
+    /// Who is on the other end of a stream, as of when it connected.
+    ///
+    /// Only on Linux and Android for now. macOS and the BSDs have `getpeereid`, but that doesn't say which
+    /// process it was, so it doesn't fill this in.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Credentials {
+        pub pid: libc::pid_t,
+        pub uid: libc::uid_t,
+        pub gid: libc::gid_t,
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    impl UnixStream {
+        pub fn peer_credentials(&self) -> io::Result<Credentials> {
+            let mut cred: libc::ucred = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(
+                    self.0.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_PEERCRED,
+                    ptr::addr_of_mut!(cred).cast(),
+                    &mut len,
+                )
+            };
+
+            match result {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(Credentials { pid: cred.pid, uid: cred.uid, gid: cred.gid }),
+            }
+        }
+    }
+}
+#[cfg(windows)]
+mod ancillary {
+    //! Unix sockets on Windows don't support ancillary data at all, so there are no handles to pass along. These
+    //! still work for plain data, so callers that only sometimes pass handles don't need a separate path.
+
+    use std::io;
+    use std::io::{IoSlice, IoSliceMut, Read, Write};
+    use std::os::windows::io::{BorrowedHandle, OwnedHandle};
+    use crate::UnixStream;
+
+    pub const MAX_FDS: usize = 0;
+
+    impl UnixStream {
+        /// Fails with [`Unsupported`](io::ErrorKind::Unsupported) unless `fds` is empty.
+        pub fn send_with_fds(&self, bufs: &[IoSlice<'_>], fds: &[BorrowedHandle<'_>]) -> io::Result<usize> {
+            if !fds.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets on Windows can't pass handles"))
+            }
+
+            (&self.0).write_vectored(bufs)
+        }
+
+        /// Never receives any handles.
+        pub fn recv_with_fds(&self, bufs: &mut [IoSliceMut<'_>], _fds: &mut Vec<OwnedHandle>) -> io::Result<usize> {
+            (&self.0).read_vectored(bufs)
+        }
+    }
+}
+#[cfg(all(feature = "tokio", unix))]
+pub mod tokio {
+    //! Async versions of the stream and listener, driven by tokio's reactor.
+    //!
+    //! Unix only for now. On Windows the socket would have to be registered with the reactor directly, which
+    //! tokio has no public API for.
+    //!
+    //! Like tokio's own sockets, anything that registers with the reactor (`pair`, `bind` and the `from_std`s)
+    //! has to be called from inside a runtime, and panics otherwise.
+
+    use std::io;
+    use std::io::{IoSlice, IoSliceMut};
+    use std::path::Path;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use ::tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use crate::SocketAddr;
+
+    #[derive(Debug)]
+    pub struct UnixStream(::tokio::net::UnixStream);
+
+    impl UnixStream {
+        pub async fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+            Ok(Self(::tokio::net::UnixStream::connect(path).await?))
+        }
+
+        pub fn pair() -> io::Result<(Self, Self)> {
+            let (sock1, sock2) = ::tokio::net::UnixStream::pair()?;
+            Ok((Self(sock1), Self(sock2)))
+        }
+
+        /// Registers a blocking stream with the reactor, switching it to nonblocking mode first.
+        pub fn from_std(stream: crate::UnixStream) -> io::Result<Self> {
+            stream.set_nonblocking(true)?;
+            Ok(Self(::tokio::net::UnixStream::from_std(stream.0)?))
+        }
+
+        /// Deregisters the stream from the reactor. It stays in nonblocking mode.
+        pub fn into_std(self) -> io::Result<crate::UnixStream> {
+            Ok(crate::UnixStream(self.0.into_std()?))
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(SocketAddr(self.0.local_addr()?.into()))
+        }
+
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(SocketAddr(self.0.peer_addr()?.into()))
+        }
+
+        pub async fn readable(&self) -> io::Result<()> {
+            self.0.readable().await
+        }
+
+        pub async fn writable(&self) -> io::Result<()> {
+            self.0.writable().await
+        }
+
+        pub fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.try_read(buf)
+        }
+
+        pub fn try_read_vectored(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+            self.0.try_read_vectored(bufs)
+        }
+
+        pub fn try_write(&self, buf: &[u8]) -> io::Result<usize> {
+            self.0.try_write(buf)
+        }
+
+        pub fn try_write_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.0.try_write_vectored(bufs)
+        }
+
+        pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+            self.0.take_error()
+        }
+    }
+
+    impl AsyncRead for UnixStream {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for UnixStream {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+        }
+
+        fn poll_write_vectored(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().0).poll_write_vectored(cx, bufs)
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            self.0.is_write_vectored()
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct UnixListener(::tokio::net::UnixListener);
+
+    impl UnixListener {
+        pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+            Ok(Self(::tokio::net::UnixListener::bind(path)?))
+        }
+
+        /// Registers a blocking listener with the reactor, switching it to nonblocking mode first.
+        pub fn from_std(listener: crate::UnixListener) -> io::Result<Self> {
+            listener.set_nonblocking(true)?;
+            Ok(Self(::tokio::net::UnixListener::from_std(listener.0)?))
+        }
+
+        /// Deregisters the listener from the reactor. It stays in nonblocking mode.
+        pub fn into_std(self) -> io::Result<crate::UnixListener> {
+            Ok(crate::UnixListener(self.0.into_std()?))
+        }
+
+        pub async fn accept(&self) -> io::Result<(UnixStream, SocketAddr)> {
+            let (stream, addr) = self.0.accept().await?;
+            Ok((UnixStream(stream), SocketAddr(addr.into())))
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(SocketAddr(self.0.local_addr()?.into()))
+        }
+
+        pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+            self.0.take_error()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{env, fs, process};
+        use ::tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use super::*;
+
+        #[::tokio::test]
+        async fn streams_work_under_the_reactor() {
+            let path = env::temp_dir().join(format!("waydows-unix-socket-tokio-{}.sock", process::id()));
+            let _ = fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).unwrap();
+            let mut client = UnixStream::connect(&path).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            fs::remove_file(&path).unwrap();
+
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+
+            let (sock1, sock2) = UnixStream::pair().unwrap();
+            sock1.writable().await.unwrap();
+            assert_eq!(sock1.try_write(b"pong").unwrap(), 4);
+            sock2.readable().await.unwrap();
+            assert_eq!(sock2.try_read(&mut buf).unwrap(), 4);
+            assert_eq!(&buf, b"pong");
+        }
+    }
+}
+
 pub use unix_stream::UnixStream;
 pub use unix_listener::UnixListener;
 pub use socket_addr::SocketAddr;
 pub use incoming::Incoming;
+pub use ancillary::MAX_FDS;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use ancillary::Credentials;
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs::File;
+    use std::io::{self, IoSlice, IoSliceMut};
+    use std::os::fd::AsFd;
+    use super::*;
+
+    #[test]
+    fn file_descriptors_arrive_with_the_data() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let file = File::open("/dev/null").unwrap();
+        let error = sender.send_with_fds(&[IoSlice::new(b"")], &[file.as_fd()]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(sender.send_with_fds(&[IoSlice::new(b"fd")], &[file.as_fd()]).unwrap(), 2);
+
+        let mut buf = [0; 2];
+        let mut fds = Vec::new();
+        assert_eq!(receiver.recv_with_fds(&mut [IoSliceMut::new(&mut buf)], &mut fds).unwrap(), 2);
+        assert_eq!(&buf, b"fd");
+        assert_eq!(fds.len(), 1);
+        assert!(File::from(fds.pop().unwrap()).metadata().is_ok());
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert_eq!(sender.peer_credentials().unwrap().pid as u32, std::process::id());
+    }
+}